@@ -1,4 +1,16 @@
-math.randomseed(os.time())
+-- Draws from the simulation's seeded generator instead of C's rand(), which is
+-- shared by every Lua state in the process
+function math.random (m, n)
+   local r = __random()
+
+   if m == nil then
+      return r
+   elseif n == nil then
+      m, n = 1, m
+   end
+
+   return m + math.floor(r * (n - m + 1))
+end
 
 function move (x, y)
    return string.format("move(%f, %f)", x, y)
@@ -8,8 +20,37 @@ function move_to_random ()
    return move(math.random(SCENE_WIDTH), math.random(SCENE_HEIGHT))
 end
 
+function look (x, y)
+   return string.format("look(%f, %f)", x, y)
+end
+
+function rotate (angle)
+   return string.format("rotate(%f)", angle)
+end
+
+function wait (seconds)
+   return string.format("wait(%f)", seconds)
+end
+
+function follow (id, distance)
+   return string.format("follow(%s, %f)", id, distance)
+end
+
+function guard (x, y, radius)
+   return string.format("guard(%f, %f, %f)", x, y, radius)
+end
+
+-- Takes a list of points, e.g. patrol({{100, 100}, {300, 100}})
+function patrol (points)
+   local parts = {}
+   for i, point in ipairs(points) do
+      parts[i] = string.format("(%f, %f)", point[1], point[2])
+   end
+   return string.format("patrol(%s)", table.concat(parts, ", "))
+end
+
 function command (id, state)
-   return string.format("command(%s, %state)", id, state)
+   return string.format("command(%s, %s)", id, state)
 end
 
 function shoot (id)
@@ -21,28 +62,97 @@ function is_shooting(state)
    return prefix == "shoot"
 end
 
+function new_unit (role, id, x, y, rotation, team)
+   return string.format("new_unit(%s, %s, %f, %f, %f, %d)", role, id, x, y, rotation, team)
+end
+
 function new_soldier (id, x, y, rotation, team)
-   return string.format("new_unit(soldier, %s, %f, %f, %f, %d)", id, x, y, rotation, team)
+   return new_unit("soldier", id, x, y, rotation, team)
 end
 
 function new_general (id, x, y, rotation, team)
-   return string.format("new_unit(general, %s, %f, %f, %f, %d)", id, x, y, rotation, team)
+   return new_unit("general", id, x, y, rotation, team)
 end
 
 function update_state (id, state)
    return string.format("update_state(%s, %s)", id, state)
 end
 
+function enqueue (id, state)
+   return string.format("enqueue(%s, %s)", id, state)
+end
+
+function prepend (id, state)
+   return string.format("prepend(%s, %s)", id, state)
+end
+
+function clear_orders (id)
+   return string.format("clear_orders(%s)", id)
+end
+
+function set_fov (id, angle, distance)
+   return string.format("set_fov(%s, %f, %f)", id, angle, distance)
+end
+
+function set_range (id, angle, distance)
+   return string.format("set_range(%s, %f, %f)", id, angle, distance)
+end
+
+-- Payloads can be any table, so messages are tables rather than strings
+function send (id, payload)
+   return {kind = "message", to = id, payload = payload}
+end
+
+function send_team (team, payload)
+   return {kind = "message", team = team, payload = payload}
+end
+
+function send_radius (radius, payload)
+   return {kind = "message", radius = radius, payload = payload}
+end
+
 function __flatten_timeline (timeline)
    local flat = {}
    local i = 1
 
    for time, deltas in pairs(timeline) do
       for _, delta in ipairs(deltas) do
-         flat[i] = string.format("(%d, %s)", time, delta)
+         if type(delta) == "table" then
+            flat[i] = {time = time, delta = delta}
+         else
+            flat[i] = string.format("(%d, %s)", time, delta)
+         end
          i = i + 1
       end
    end
 
    return flat
 end
+
+local function encodable (value)
+   local t = type(value)
+   return t == "table" or t == "number" or t == "string" or t == "boolean"
+end
+
+-- Flattens any value into a list of tagged primitives that Rust can read
+-- without nesting, e.g. {x=1} becomes {"{", "s", "x", "n", 1, "}"}
+function __encode (value, tokens)
+   tokens = tokens or {}
+
+   if type(value) == "table" then
+      tokens[#tokens + 1] = "{"
+      for k, v in pairs(value) do
+         local key_type = type(k)
+         if (key_type == "string" or key_type == "number") and encodable(v) then
+            __encode(k, tokens)
+            __encode(v, tokens)
+         end
+      end
+      tokens[#tokens + 1] = "}"
+   elseif encodable(value) then
+      tokens[#tokens + 1] = string.sub(type(value), 1, 1)
+      tokens[#tokens + 1] = value
+   end
+
+   return tokens
+end
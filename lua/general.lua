@@ -4,12 +4,6 @@ function general_on_state_change (self)
    end
 end
 
-function general_on_collision (self, other)
-   if other["role"] == "bullet" then
-      return "dead"
-   end
-end
-
 function general_on_enter_view (self, other)
    if self["team"] == other["team"] and
       other["role"] == "soldier" and
@@ -4,9 +4,9 @@ function soldier_on_state_change (self)
    end
 end
 
-function soldier_on_collision (self, other)
-   if other["role"] == "bullet" then
-      return "dead"
+function soldier_on_damaged (self, other)
+   if self["health"] <= 50 then
+      return move_to_random()
    end
 end
 
@@ -0,0 +1,91 @@
+use std::str::FromStr;
+use time;
+
+use headless;
+use random::Seed;
+
+pub const USAGE: &'static str = "usage: generals [options]
+
+    --scripts DIR         directory of Lua scripts to load (default ./lua)
+    --scenario NAME       scenario script NAME.lua, with a NAME function returning the
+                          timeline (default timeline)
+    --scene WxH           size of the scene, overrides the scenario's (default 800x800)
+    --window WxH          size of the window (default the scene size)
+    --log FILTERS         log filters, same syntax as RUST_LOG
+    --headless [SECONDS]  run without a window (default 60 seconds)
+    --seed N              simulation seed (default the current time)
+    --record PATH         record the battle to PATH
+    --replay PATH         replay a recorded battle";
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub script_dir: String,
+    pub scenario: String,
+    pub scene_size: Option<[u32; 2]>,
+    pub window_size: Option<[u32; 2]>,
+    pub log_filters: Option<String>,
+    pub headless: Option<f64>,
+    pub seed: Seed,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+}
+
+impl Config {
+    pub fn from_args<I: Iterator<Item = String>>(args: I) -> Result<Config, String> {
+        let mut config = Config {
+            script_dir: "./lua".to_string(),
+            scenario: "timeline".to_string(),
+            scene_size: None,
+            window_size: None,
+            log_filters: None,
+            headless: None,
+            seed: time::get_time().sec as Seed,
+            record: None,
+            replay: None,
+        };
+
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--scripts" => config.script_dir = try!(arg_value(&arg, args.next())),
+                "--scenario" => config.scenario = try!(arg_value(&arg, args.next())),
+                "--scene" => config.scene_size = Some(try!(arg_size(&arg, args.next()))),
+                "--window" => config.window_size = Some(try!(arg_size(&arg, args.next()))),
+                "--log" => config.log_filters = Some(try!(arg_value(&arg, args.next()))),
+                "--headless" => {
+                    let duration = args.peek().and_then(|arg| f64::from_str(arg).ok());
+                    if duration.is_some() {
+                        args.next();
+                    }
+                    config.headless = Some(duration.unwrap_or(headless::DEFAULT_DURATION));
+                }
+                "--seed" => {
+                    config.seed = match args.next().and_then(|arg| Seed::from_str(&arg).ok()) {
+                        Some(seed) => seed,
+                        None => return Err("--seed expects an unsigned integer".to_string()),
+                    }
+                }
+                "--record" => config.record = Some(try!(arg_value(&arg, args.next()))),
+                "--replay" => config.replay = Some(try!(arg_value(&arg, args.next()))),
+                _ => return Err(format!("unknown argument: {}", arg)),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+fn arg_value(arg: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("{} expects a value", arg))
+}
+
+// Sizes are written as `<width>x<height>`, e.g. `1200x800`
+fn arg_size(arg: &str, value: Option<String>) -> Result<[u32; 2], String> {
+    let value = try!(value.ok_or_else(|| format!("{} expects a size", arg)));
+    let mut parts = value.splitn(2, 'x').map(u32::from_str);
+
+    match (parts.next(), parts.next()) {
+        (Some(Ok(width)), Some(Ok(height))) if width > 0 && height > 0 => Ok([width, height]),
+        _ => Err(format!("{} expects a size like 800x600, found {}", arg, value)),
+    }
+}
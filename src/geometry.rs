@@ -1,9 +1,9 @@
 use nalgebra::{Isometry2, Vector1, Vector2};
 use std::f64;
 
-pub const SCENE_SIZE: [u32; 2] = [800, 800];
 const PI: f64 = f64::consts::PI;
 const TWO_PI: f64 = f64::consts::PI * 2.0;
+const CONE_SEGMENTS: usize = 8;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Pose {
@@ -74,11 +74,52 @@ impl Pose {
         Pose::new(self.x, self.y, rotation)
     }
 
+    pub fn clamp(&self, width: f64, height: f64) -> Pose {
+        Pose::new(self.x.max(0.0).min(width),
+                  self.y.max(0.0).min(height),
+                  self.rotation)
+    }
+
+    // Mirrors the pose across the vertical line at `x`
+    pub fn reflect_x(&self, x: f64) -> Pose {
+        let mut rotation = PI - self.rotation;
+        if rotation < 0.0 {
+            rotation += TWO_PI;
+        }
+        Pose::new(2.0 * x - self.x, self.y, rotation)
+    }
+
+    // Mirrors the pose across the horizontal line at `y`
+    pub fn reflect_y(&self, y: f64) -> Pose {
+        Pose::new(self.x, 2.0 * y - self.y, (TWO_PI - self.rotation) % TWO_PI)
+    }
+
     pub fn isometry(&self) -> Isometry2<f64> {
         Isometry2::new(Vector2::new(self.x, self.y), Vector1::new(self.rotation))
     }
 
+    // Screen y grows downwards, the scene's height is added back when drawing
     pub fn render_pose(&self) -> (f64, f64, f64) {
-        (self.x, SCENE_SIZE[1] as f64 - self.y, -self.rotation)
+        (self.x, -self.y, -self.rotation)
     }
 }
+
+// The smallest angle between two headings, whichever way round
+pub fn angle_between(a: f64, b: f64) -> f64 {
+    let delta = (a - b).abs() % TWO_PI;
+    delta.min(TWO_PI - delta)
+}
+
+// A sector facing along the x axis, `angle` wide and reaching `distance` away,
+// capped at a half circle so that it stays convex
+pub fn cone(angle: f64, distance: f64) -> Vec<[f64; 2]> {
+    let angle = angle.max(0.0).min(PI);
+    let mut points = vec![[0.0, 0.0]];
+
+    for i in 0..CONE_SEGMENTS + 1 {
+        let theta = -angle / 2.0 + angle * i as f64 / CONE_SEGMENTS as f64;
+        points.push([theta.cos() * distance, theta.sin() * distance]);
+    }
+
+    points
+}
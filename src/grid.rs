@@ -0,0 +1,100 @@
+use nalgebra::Point2;
+use ncollide::bounding_volume::AABB;
+use std::collections::{BTreeSet, HashMap};
+
+use unit::{Id, Ids};
+
+pub type Bounds = AABB<Point2<f64>>;
+
+type Cell = (i64, i64);
+type CellRange = (Cell, Cell);
+
+// Uniform grid used as the broad phase for collision and view detection, each
+// unit is registered in every cell its bounds overlap
+pub struct Grid {
+    cell_size: f64,
+    cells: HashMap<Cell, Ids>,
+    ranges: HashMap<Id, CellRange>,
+}
+
+impl Grid {
+    pub fn new(cell_size: f64) -> Grid {
+        Grid {
+            cell_size: cell_size,
+            cells: HashMap::new(),
+            ranges: HashMap::new(),
+        }
+    }
+
+    pub fn update(&mut self, id: Id, bounds: &Bounds) {
+        let range = self.cell_range(bounds);
+
+        match self.ranges.get(&id) {
+            Some(current) if *current == range => return,
+            _ => {}
+        }
+
+        self.remove(&id);
+
+        for cell in Self::cells_in(range) {
+            self.cells.entry(cell).or_insert_with(BTreeSet::new).insert(id);
+        }
+        self.ranges.insert(id, range);
+    }
+
+    pub fn remove(&mut self, id: &Id) {
+        let range = match self.ranges.remove(id) {
+            Some(range) => range,
+            None => return,
+        };
+
+        for cell in Self::cells_in(range) {
+            let empty = match self.cells.get_mut(&cell) {
+                Some(ids) => {
+                    ids.remove(id);
+                    ids.is_empty()
+                }
+                None => false,
+            };
+
+            if empty {
+                self.cells.remove(&cell);
+            }
+        }
+    }
+
+    // Every unit sharing a cell with `bounds`, a superset of the units overlapping it
+    pub fn candidates(&self, bounds: &Bounds) -> Ids {
+        let mut candidates = BTreeSet::new();
+
+        for cell in Self::cells_in(self.cell_range(bounds)) {
+            if let Some(ids) = self.cells.get(&cell) {
+                candidates.extend(ids.iter().cloned());
+            }
+        }
+
+        candidates
+    }
+
+    fn cell_range(&self, bounds: &Bounds) -> CellRange {
+        let (mins, maxs) = (bounds.mins(), bounds.maxs());
+        ((self.cell(mins.x), self.cell(mins.y)), (self.cell(maxs.x), self.cell(maxs.y)))
+    }
+
+    fn cell(&self, coord: f64) -> i64 {
+        (coord / self.cell_size).floor() as i64
+    }
+
+    fn cells_in(range: CellRange) -> Vec<Cell> {
+        let ((min_x, min_y), (max_x, max_y)) = range;
+        let mut cells = vec![];
+
+        for x in min_x..max_x + 1 {
+            for y in min_y..max_y + 1 {
+                cells.push((x, y));
+            }
+        }
+
+        cells
+    }
+}
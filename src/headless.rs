@@ -0,0 +1,62 @@
+use piston_window::UpdateArgs;
+use std::collections::{BTreeMap, BTreeSet};
+
+use State;
+use interpreter::Error;
+
+pub const DEFAULT_DURATION: f64 = 60.0;
+pub const DEFAULT_DT: f64 = 1.0 / 60.0;
+
+type Summary = BTreeMap<usize, BTreeMap<String, usize>>;
+
+pub fn run(mut state: State, duration: f64, dt: f64) -> Result<(), Error> {
+    let args = UpdateArgs { dt: dt };
+    let mut teams = BTreeSet::new();
+
+    while state.elapsed < duration {
+        try!(state.update(&args));
+
+        let summary = summarize(&state);
+        teams.extend(summary.keys().cloned());
+
+        if teams.iter().any(|team| !summary.contains_key(team)) {
+            info!(target: "headless", "team eliminated at {:.*}", 2, state.elapsed);
+            break;
+        }
+    }
+
+    print_summary(&state, &teams);
+    Ok(())
+}
+
+fn summarize(state: &State) -> Summary {
+    let mut summary = Summary::new();
+
+    for unit in state.units.values().filter(|u| !u.role.projectile) {
+        *summary.entry(unit.team)
+            .or_insert_with(BTreeMap::new)
+            .entry(unit.role.name.clone())
+            .or_insert(0) += 1;
+    }
+
+    summary
+}
+
+fn print_summary(state: &State, teams: &BTreeSet<usize>) {
+    let summary = summarize(state);
+
+    println!("seed {}", state.seed);
+    println!("elapsed {:.*}s", 2, state.elapsed);
+    for team in teams {
+        let units = match summary.get(team) {
+            Some(roles) => {
+                roles.iter()
+                    .map(|(role, count)| format!("{} {}", count, role))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            }
+            None => "eliminated".to_string(),
+        };
+        println!("team {}: {}", team, units);
+    }
+}
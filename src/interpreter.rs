@@ -1,24 +1,68 @@
 use hlua::{self, Lua, LuaTable};
+use std::fmt;
 use std::fs;
 use std::io;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::thread;
-use std::time;
 
-use geometry::SCENE_SIZE;
-use parser::{self, TokenType};
-use unit::{Id, Unit, UnitRole, UnitState};
+use config::Config;
+use obstacle::Obstacle;
+use parser::{self, Expected, Expr};
+use random::{Random, Stream};
+use role::{Role, Roles};
+use scene::{self, Scene};
+use unit::{Id, Unit, UnitState};
+use value::{self, Value};
+use watcher::Watcher;
+
+pub type ExecState = (String, UnitSnapshot, Vec<Argument>);
+
+// What a handler is passed after `self`
+pub enum Argument {
+    Nil,
+    Number(f64),
+    String(String),
+    Unit(UnitSnapshot),
+    Value(Value),
+}
 
-pub type ExecState = (String, UnitSnapshot, Option<UnitSnapshot>);
+pub enum Request {
+    Exec(ExecState),
+    ExecTeam(TeamSnapshot),
+    Reload(Vec<PathBuf>),
+    Sync,
+}
 
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
-    DeltaChannelClosed(mpsc::SendError<ExecState>),
+    DeltaChannelClosed(mpsc::SendError<Request>),
+    InterpreterStopped(mpsc::RecvError),
+    InvalidReplay(String),
+    InvalidValue(parser::Error),
     LuaException(hlua::LuaError),
     LuaIndexNotFound(String),
+    LuaTraceback(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidValue(ref err) => write!(f, "invalid return value, {}", err),
+            Error::LuaException(hlua::LuaError::SyntaxError(ref message)) |
+            Error::LuaException(hlua::LuaError::ExecutionError(ref message)) => {
+                write!(f, "{}", message)
+            }
+            Error::LuaTraceback(ref traceback) => write!(f, "{}", traceback),
+            ref err => write!(f, "{:?}", err),
+        }
+    }
 }
 
 impl From<io::Error> for Error {
@@ -33,27 +77,94 @@ impl From<hlua::LuaError> for Error {
     }
 }
 
-impl From<mpsc::SendError<ExecState>> for Error {
-    fn from(err: mpsc::SendError<ExecState>) -> Error {
+impl From<parser::Error> for Error {
+    fn from(err: parser::Error) -> Error {
+        Error::InvalidValue(err)
+    }
+}
+
+impl From<mpsc::SendError<Request>> for Error {
+    fn from(err: mpsc::SendError<Request>) -> Error {
         Error::DeltaChannelClosed(err)
     }
 }
 
+impl From<mpsc::RecvError> for Error {
+    fn from(err: mpsc::RecvError) -> Error {
+        Error::InterpreterStopped(err)
+    }
+}
+
 #[derive(Debug)]
 pub enum EventType {
     Collision,
+    Command,
+    Damaged,
+    Death,
     EnterView,
+    ExitCollision,
     ExitView,
+    Message,
+    OutOfAmmo,
+    Reload,
+    Spawn,
     StateChange,
+    Tick,
+    Unreachable,
 }
 
 impl ToString for EventType {
     fn to_string(&self) -> String {
         match *self {
             EventType::Collision => String::from("collision"),
+            EventType::Command => String::from("command"),
+            EventType::Damaged => String::from("damaged"),
+            EventType::Death => String::from("death"),
             EventType::EnterView => String::from("enter_view"),
+            EventType::ExitCollision => String::from("exit_collision"),
             EventType::ExitView => String::from("exit_view"),
+            EventType::Message => String::from("message"),
+            EventType::OutOfAmmo => String::from("out_of_ammo"),
+            EventType::Reload => String::from("reload"),
+            EventType::Spawn => String::from("spawn"),
             EventType::StateChange => String::from("state_change"),
+            EventType::Tick => String::from("tick"),
+            EventType::Unreachable => String::from("unreachable"),
+        }
+    }
+}
+
+// Names of the deltas a handler can return in place of a new state
+const DELTA_KINDS: [&'static str; 7] = ["clear_orders",
+                                         "enqueue",
+                                         "new_unit",
+                                         "prepend",
+                                         "set_fov",
+                                         "set_range",
+                                         "update_state"];
+
+// Who a message comes from, a unit or a team's commander
+#[derive(Clone, Copy, Debug)]
+pub enum Origin {
+    Unit(Id),
+    Team(usize),
+}
+
+// Who a message is for, the sender never gets its own message
+#[derive(Debug)]
+pub enum Recipients {
+    Unit(Id),
+    Team(usize),
+    // Every unit within this distance of the sender
+    Radius(f64),
+}
+
+impl ToString for Recipients {
+    fn to_string(&self) -> String {
+        match *self {
+            Recipients::Unit(id) => format!("unit({})", id),
+            Recipients::Team(team) => format!("team({})", team),
+            Recipients::Radius(radius) => format!("radius({:?})", radius),
         }
     }
 }
@@ -61,39 +172,192 @@ impl ToString for EventType {
 #[derive(Debug)]
 pub enum Delta {
     UpdateState(Id, UnitState),
-    NewUnit(UnitRole, Id, f64, f64, f64, usize),
+    NewUnit(String, Id, f64, f64, f64, usize),
+    SetFov(Id, f64, f64),
+    SetRange(Id, f64, f64),
+    Enqueue(Id, UnitState),
+    Prepend(Id, UnitState),
+    ClearOrders(Id),
+}
+
+impl ToString for Delta {
+    fn to_string(&self) -> String {
+        match *self {
+            Delta::UpdateState(id, ref state) => {
+                format!("update_state({}, {})", id, state.to_string())
+            }
+            Delta::NewUnit(ref role, id, x, y, rotation, team) => {
+                format!("new_unit({}, {}, {:?}, {:?}, {:?}, {})",
+                        role,
+                        id,
+                        x,
+                        y,
+                        rotation,
+                        team)
+            }
+            Delta::SetFov(id, angle, distance) => {
+                format!("set_fov({}, {:?}, {:?})", id, angle, distance)
+            }
+            Delta::SetRange(id, angle, distance) => {
+                format!("set_range({}, {:?}, {:?})", id, angle, distance)
+            }
+            Delta::Enqueue(id, ref state) => format!("enqueue({}, {})", id, state.to_string()),
+            Delta::Prepend(id, ref state) => format!("prepend({}, {})", id, state.to_string()),
+            Delta::ClearOrders(id) => format!("clear_orders({})", id),
+        }
+    }
 }
 
 impl FromStr for Delta {
     type Err = parser::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match parser::read_fn(s) {
-            Ok(("new_unit", s)) => {
-                let (role, s) = try!(parser::read_symbol(s));
-                let (id, s) = try!(parser::read_id(s));
-                let (x, s) = try!(parser::read_float(s));
-                let (y, s) = try!(parser::read_float(s));
-                let (rotation, s) = try!(parser::read_float(s));
-                let (team, _) = try!(parser::read_int(s));
-
-                match UnitRole::from_str(role) {
-                    Ok(r) => Ok(Delta::NewUnit(r, id, x, y, rotation, team)),
-                    Err(string) => Err((string, TokenType::Other)),
-                }
+        Delta::from_expr(&try!(parser::parse(s)))
+    }
+}
+
+impl Delta {
+    pub fn from_expr(expr: &Expr) -> Result<Delta, parser::Error> {
+        let (name, args, column) = match *expr {
+            Expr::Call(ref name, ref args, column) => (name.as_str(), args, column),
+            _ => return Err(expr.error(Expected::Delta)),
+        };
+
+        match name {
+            "new_unit" => {
+                let args = try!(parser::arguments(args, 6, column));
+                Ok(Delta::NewUnit(try!(args[0].symbol()).to_string(),
+                                  try!(args[1].id()),
+                                  try!(args[2].float()),
+                                  try!(args[3].float()),
+                                  try!(args[4].float()),
+                                  try!(args[5].int())))
             }
-            Ok(("update_state", s)) => {
-                let (id, s) = try!(parser::read_id(s));
-                let (state, _) = try!(parser::read_rest(s));
+            "update_state" => {
+                let args = try!(parser::arguments(args, 2, column));
+                Ok(Delta::UpdateState(try!(args[0].id()), try!(UnitState::from_expr(&args[1]))))
+            }
+            "set_fov" => {
+                let args = try!(parser::arguments(args, 3, column));
+                Ok(Delta::SetFov(try!(args[0].id()), try!(args[1].float()), try!(args[2].float())))
+            }
+            "set_range" => {
+                let args = try!(parser::arguments(args, 3, column));
+                Ok(Delta::SetRange(try!(args[0].id()),
+                                   try!(args[1].float()),
+                                   try!(args[2].float())))
+            }
+            "enqueue" => {
+                let args = try!(parser::arguments(args, 2, column));
+                Ok(Delta::Enqueue(try!(args[0].id()), try!(UnitState::from_expr(&args[1]))))
+            }
+            "prepend" => {
+                let args = try!(parser::arguments(args, 2, column));
+                Ok(Delta::Prepend(try!(args[0].id()), try!(UnitState::from_expr(&args[1]))))
+            }
+            "clear_orders" => {
+                let args = try!(parser::arguments(args, 1, column));
+                Ok(Delta::ClearOrders(try!(args[0].id())))
+            }
+            _ => Err(expr.error(Expected::Delta)),
+        }
+    }
 
-                match UnitState::from_str(state) {
-                    Ok(s) => Ok(Delta::UpdateState(id, s)),
-                    Err(e) => Err(e),
-                }
+    pub fn from_value(value: &Value) -> Result<Delta, parser::Error> {
+        let kind = match *value {
+            Value::String(ref s) => return Delta::from_str(s),
+            _ => try!(value.string("kind")),
+        };
+
+        match kind {
+            "new_unit" => {
+                Ok(Delta::NewUnit(try!(value.string("role")).to_string(),
+                                  try!(value.id("id")),
+                                  try!(value.number("x")),
+                                  try!(value.number("y")),
+                                  try!(value.number("rotation")),
+                                  try!(value.int("team"))))
+            }
+            "update_state" => {
+                Ok(Delta::UpdateState(try!(value.id("id")), try!(Self::state_field(value))))
+            }
+            "enqueue" => Ok(Delta::Enqueue(try!(value.id("id")), try!(Self::state_field(value)))),
+            "prepend" => Ok(Delta::Prepend(try!(value.id("id")), try!(Self::state_field(value)))),
+            "clear_orders" => Ok(Delta::ClearOrders(try!(value.id("id")))),
+            "set_fov" => {
+                Ok(Delta::SetFov(try!(value.id("id")),
+                                 try!(value.number("angle")),
+                                 try!(value.number("distance"))))
             }
-            _ => Err((String::from(s), TokenType::Other)),
+            "set_range" => {
+                Ok(Delta::SetRange(try!(value.id("id")),
+                                   try!(value.number("angle")),
+                                   try!(value.number("distance"))))
+            }
+            _ => Err(parser::Error::field("kind", Expected::Delta)),
+        }
+    }
+
+    fn state_field(value: &Value) -> Result<UnitState, parser::Error> {
+        match value.get("state") {
+            Some(state) => UnitState::from_value(state),
+            None => Err(parser::Error::field("state", Expected::State)),
+        }
+    }
+
+    // The unit a delta acts on
+    fn unit(&self) -> Option<Id> {
+        match *self {
+            Delta::UpdateState(id, _) |
+            Delta::SetFov(id, _, _) |
+            Delta::SetRange(id, _, _) |
+            Delta::Enqueue(id, _) |
+            Delta::Prepend(id, _) |
+            Delta::ClearOrders(id) => Some(id),
+            Delta::NewUnit(_, _, _, _, _, _) => None,
         }
     }
+
+    // Whether a value returned by a handler names a delta rather than a state
+    fn is_delta(value: &Value) -> bool {
+        let kind = match *value {
+            Value::String(ref s) => s.split('(').next().unwrap_or("").trim(),
+            _ => value.string("kind").unwrap_or(""),
+        };
+        DELTA_KINDS.contains(&kind)
+    }
+}
+
+// Messages only reach Lua handlers, so unlike deltas they are never recorded.
+// Payloads are any Lua value, so messages only come as tables.
+#[derive(Debug)]
+pub struct Message {
+    pub origin: Origin,
+    pub recipients: Recipients,
+    pub payload: Value,
+}
+
+impl Message {
+    // The sender is whoever's handler returned the message, never up to the script
+    fn from_value(origin: Origin, value: &Value) -> Result<Message, parser::Error> {
+        let recipients = if value.get("to").is_some() {
+            Recipients::Unit(try!(value.id("to")))
+        } else if value.get("team").is_some() {
+            Recipients::Team(try!(value.int("team")))
+        } else {
+            Recipients::Radius(try!(value.number("radius")))
+        };
+
+        Ok(Message {
+            origin: origin,
+            recipients: recipients,
+            payload: value.get("payload").cloned().unwrap_or_else(|| Value::Table(BTreeMap::new())),
+        })
+    }
+
+    fn is_message(value: &Value) -> bool {
+        value.string("kind").ok() == Some("message")
+    }
 }
 
 pub struct UnitSnapshot {
@@ -101,45 +365,83 @@ pub struct UnitSnapshot {
     x: f64,
     y: f64,
     team: usize,
-    role: UnitRole,
+    role: String,
     state: UnitState,
+    health: f64,
+    ammo: usize,
+    reserve: usize,
+    orders: Vec<UnitState>,
 }
 
 impl UnitSnapshot {
-    fn new(unit: &Unit) -> UnitSnapshot {
+    pub fn new(unit: &Unit) -> UnitSnapshot {
         let (x, y) = unit.xy();
         UnitSnapshot {
             id: unit.id,
             x: x,
             y: y,
             team: unit.team,
-            role: unit.role,
+            role: unit.role.name.clone(),
             state: unit.state.clone(),
+            health: unit.health,
+            ammo: unit.weapon.as_ref().map_or(0, |weapon| weapon.magazine),
+            reserve: unit.weapon.as_ref().map_or(0, |weapon| weapon.reserve),
+            orders: unit.orders().iter().cloned().collect(),
+        }
+    }
+
+    // Obstacles are shown to handlers as idle units of team 0
+    pub fn obstacle(obstacle: &Obstacle) -> UnitSnapshot {
+        UnitSnapshot {
+            id: obstacle.id,
+            x: obstacle.pose.x,
+            y: obstacle.pose.y,
+            team: 0,
+            role: "obstacle".to_string(),
+            state: UnitState::Idle,
+            health: 0.0,
+            ammo: 0,
+            reserve: 0,
+            orders: vec![],
         }
     }
 }
 
-fn read_dir(dir: &str) -> Result<Vec<String>, io::Error> {
-    try!(fs::read_dir(dir))
-        .map(|dir| dir.unwrap().path())
-        .map(fs::File::open)
-        .map(|file| {
-            let mut s = String::new();
-            try!(file).read_to_string(&mut s).unwrap();
-            Ok(s)
-        })
-        .collect::<Result<Vec<String>, io::Error>>()
+// What a team's commander knows of the battle, its own units and every other
+// unit that any of them can see
+pub struct TeamSnapshot {
+    pub team: usize,
+    pub units: Vec<UnitSnapshot>,
+    pub visible: Vec<UnitSnapshot>,
 }
 
-fn load_lua_scripts(lua: &mut Lua) -> Result<(), Error> {
-    for script in &try!(read_dir("./lua")) {
-        try!(lua.execute::<()>(script));
+// Sorted so that scripts always load in the same order
+pub fn script_paths(dir: &str) -> Result<Vec<PathBuf>, io::Error> {
+    let mut paths = vec![];
+
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+        if path.extension().map_or(false, |ext| ext == "lua") {
+            paths.push(path);
+        }
     }
+
+    paths.sort();
+    Ok(paths)
+}
+
+fn load_lua_script(lua: &mut Lua, path: &Path) -> Result<(), Error> {
+    let mut script = String::new();
+    try!(try!(fs::File::open(path)).read_to_string(&mut script));
+    try!(lua.execute::<()>(&script));
     Ok(())
 }
 
-fn gen_uuid() -> String {
-    Id::new_v4().hyphenated().to_string()
+fn load_lua_scripts(lua: &mut Lua, dir: &str) -> Result<(), Error> {
+    for path in try!(script_paths(dir)) {
+        try!(load_lua_script(lua, &path));
+    }
+    Ok(())
 }
 
 #[derive(Debug)]
@@ -152,155 +454,472 @@ impl FromStr for TimelineEvent {
     type Err = parser::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match parser::read_tuple(s) {
-            Ok(s) => {
-                let (time, s) = try!(parser::read_int(s));
-                let (delta, _) = try!(parser::read_rest(s));
-
-                match Delta::from_str(delta) {
-                    Ok(d) => {
-                        Ok(TimelineEvent {
-                            time: time,
-                            delta: d,
-                        })
-                    }
-                    Err(e) => Err(e),
-                }
-            }
-            _ => Err((String::from(s), TokenType::Other)),
+        let expr = try!(parser::parse(s));
+        let (args, column) = match expr {
+            Expr::Tuple(ref args, column) => (args, column),
+            _ => return Err(expr.error(Expected::TimelineEvent)),
+        };
+        let args = try!(parser::arguments(args, 2, column));
+
+        Ok(TimelineEvent {
+            time: try!(args[0].int()),
+            delta: try!(Delta::from_expr(&args[1])),
+        })
+    }
+}
+
+impl TimelineEvent {
+    fn from_value(value: &Value) -> Result<TimelineEvent, parser::Error> {
+        if let Value::String(ref s) = *value {
+            return TimelineEvent::from_str(s);
         }
+
+        let delta = match value.get("delta") {
+            Some(delta) => try!(Delta::from_value(delta)),
+            None => return Err(parser::Error::field("delta", Expected::Delta)),
+        };
+
+        Ok(TimelineEvent {
+            time: try!(value.int("time")),
+            delta: delta,
+        })
     }
 }
 
 pub struct Interpreter {
-    tx: Sender<ExecState>,
+    tx: Sender<Request>,
+    sync_rx: Receiver<()>,
+    timeline: Vec<TimelineEvent>,
+    watcher: Watcher,
+    pub scene: Scene,
+    pub roles: Roles,
 }
 
 impl Interpreter {
-    pub fn new(delta_tx: Sender<Delta>) -> Interpreter {
-        let mut lua = Lua::new();
-        lua.openlibs();
-
-        let (tx, rx): (Sender<ExecState>, Receiver<ExecState>) = mpsc::channel();
-        let delta_tx_cloned = delta_tx.clone();
-
+    pub fn new(delta_tx: Sender<Delta>,
+               message_tx: Sender<Message>,
+               config: &Config)
+               -> Interpreter {
+        let (tx, rx): (Sender<Request>, Receiver<Request>) = mpsc::channel();
+        let (sync_tx, sync_rx) = mpsc::channel();
+        let (timeline_tx, timeline_rx) = mpsc::channel();
+
+        let timeline_config = config.clone();
         thread::spawn(move || {
-            let mut lua = Self::new_lua_instance();
+            let config = timeline_config;
+            let mut lua = Self::new_lua_instance(&config, Stream::Timeline);
+            if let Err(err) = Self::load_scenario(&mut lua, &config) {
+                error!(target: "lua", "failed to load scenario {}: {}", config.scenario, err);
+            }
+
+            let scene = Self::read_scene(&mut lua, &config);
+            Self::set_scene(&mut lua, &scene);
+            let roles = Self::read_roles(&mut lua);
 
-            let mut timeline = match Self::generate_timeline(&mut lua) {
+            let mut timeline = match Self::generate_timeline(&mut lua, &config.scenario) {
                 Ok(events) => events,
-                Err(err) => panic!(err),
+                Err(err) => {
+                    error!(target: "lua", "timeline failed: {}", err);
+                    vec![]
+                }
             };
-            timeline.sort_by(|l, r| l.time.cmp(&r.time));
-
-            let mut current_time = 0;
-            for event in timeline {
-                let wait_time = event.time - current_time;
-                let duration = time::Duration::from_secs(wait_time as u64);
+            // Latest events first, so the next one due can be popped off the back
+            timeline.sort_by(|l, r| r.time.cmp(&l.time));
 
-                info!(target: "timeline", "waiting {}", wait_time);
-                thread::sleep(duration);
-
-                current_time += wait_time;
-                info!(target: "timeline", "{:?}", event);
-                delta_tx_cloned.send(event.delta).unwrap();
-            }
+            timeline_tx.send((timeline, scene, roles)).unwrap();
         });
 
-        thread::spawn(move || {
-            let mut lua = Self::new_lua_instance();
+        // Handlers only start once the scenario has settled on the scene
+        let (timeline, scene, roles) = timeline_rx.recv().unwrap();
 
-            while let Ok(state) = rx.recv() {
-                match Self::exec_function(&mut lua, state) {
-                    Ok(Some(delta)) => delta_tx.send(delta).unwrap(),
-                    Ok(None) => {}
-                    Err(err) => panic!(err),
+        let handler_config = config.clone();
+        let handler_scene = scene.clone();
+        thread::spawn(move || {
+            let mut lua = Self::new_lua_instance(&handler_config, Stream::Handlers);
+            Self::set_scene(&mut lua, &handler_scene);
+
+            while let Ok(request) = rx.recv() {
+                let state = match request {
+                    Request::Exec(state) => state,
+                    Request::ExecTeam(team) => {
+                        match Self::exec_team_function(&mut lua, &team) {
+                            Ok((deltas, messages)) => {
+                                for delta in deltas {
+                                    delta_tx.send(delta).unwrap();
+                                }
+                                for message in messages {
+                                    message_tx.send(message).unwrap();
+                                }
+                            }
+                            Err(err) => {
+                                error!(target: "lua",
+                                       "team_on_tick failed for team {}: {}",
+                                       team.team,
+                                       err)
+                            }
+                        }
+                        continue;
+                    }
+                    Request::Reload(paths) => {
+                        Self::reload_scripts(&mut lua, &paths);
+                        continue;
+                    }
+                    Request::Sync => {
+                        sync_tx.send(()).unwrap();
+                        continue;
+                    }
+                };
+
+                let (function, self_unit, args) = state;
+
+                match Self::exec_function(&mut lua, &function, &self_unit, &args) {
+                    Ok((deltas, messages)) => {
+                        for delta in deltas {
+                            delta_tx.send(delta).unwrap();
+                        }
+                        for message in messages {
+                            message_tx.send(message).unwrap();
+                        }
+                    }
+                    Err(err) => {
+                        error!(target: "lua",
+                               "{} failed for unit {}: {}",
+                               function,
+                               self_unit.id,
+                               err);
+
+                        // Park the unit so a broken handler cannot keep it moving or shooting
+                        if self_unit.state != UnitState::Idle {
+                            delta_tx.send(Delta::UpdateState(self_unit.id, UnitState::Idle))
+                                .unwrap();
+                        }
+                    }
                 }
             }
         });
 
-        Interpreter { tx: tx }
+        Interpreter {
+            tx: tx,
+            sync_rx: sync_rx,
+            timeline: timeline,
+            watcher: Watcher::new(&config.script_dir),
+            scene: scene,
+            roles: Roles::new(roles),
+        }
+    }
+
+    // Blocks until every handler sent so far has run and sent its delta
+    pub fn sync(&mut self) -> Result<(), Error> {
+        try!(self.tx.send(Request::Sync));
+        try!(self.sync_rx.recv());
+        Ok(())
+    }
+
+    // Queues changed scripts to be loaded into the handler state, call between
+    // ticks so every handler of a tick runs against the same scripts
+    pub fn reload_changed_scripts(&mut self) -> Result<(), Error> {
+        let changed = self.watcher.changed();
+        if !changed.is_empty() {
+            try!(self.tx.send(Request::Reload(changed)));
+        }
+        Ok(())
+    }
+
+    pub fn timeline_deltas(&mut self, elapsed: f64) -> Vec<Delta> {
+        let mut deltas = vec![];
+
+        while self.timeline.last().map_or(false, |e| e.time as f64 <= elapsed) {
+            let event = self.timeline.pop().unwrap();
+            info!(target: "timeline", "{:.*} {:?}", 2, elapsed, event);
+            deltas.push(event.delta);
+        }
+
+        deltas
     }
 
     pub fn exec(&mut self,
-                role: &UnitRole,
+                role: &Role,
                 event_type: &EventType,
                 unit: &Unit,
-                other: Option<&Unit>)
+                other: Option<UnitSnapshot>)
                 -> Result<(), Error> {
-        let function = format!("{}_on_{}", role.to_string(), event_type.to_string());
-        try!(self.tx
-            .send((function, UnitSnapshot::new(unit), other.map(UnitSnapshot::new))));
+        self.exec_args(role, event_type, unit, other.into_iter().map(Argument::Unit).collect())
+    }
+
+    pub fn exec_args(&mut self,
+                     role: &Role,
+                     event_type: &EventType,
+                     unit: &Unit,
+                     args: Vec<Argument>)
+                     -> Result<(), Error> {
+        let function = format!("{}_on_{}", role.name, event_type.to_string());
+        try!(self.tx.send(Request::Exec((function, UnitSnapshot::new(unit), args))));
         Ok(())
     }
 
-    fn exec_function(lua: &mut Lua, state: ExecState) -> Result<Option<Delta>, Error> {
-        let (function, self_unit, other_unit) = state;
+    pub fn exec_team(&mut self, team: TeamSnapshot) -> Result<(), Error> {
+        try!(self.tx.send(Request::ExecTeam(team)));
+        Ok(())
+    }
 
+    fn exec_function(lua: &mut Lua,
+                     function: &str,
+                     self_unit: &UnitSnapshot,
+                     args: &[Argument])
+                     -> Result<(Vec<Delta>, Vec<Message>), Error> {
         if try!(lua.execute::<bool>(&format!("return _G[\"{}\"] == nil", function))) {
-            return Ok(None);
+            return Ok((vec![], vec![]));
         }
 
-        Self::set_unit(lua, "__self", &self_unit);
+        Self::set_unit(lua, "__self", self_unit);
+
+        let mut names = vec!["__self".to_string()];
+        for (i, arg) in args.iter().enumerate() {
+            let name = format!("__arg{}", i + 1);
+            match *arg {
+                Argument::Nil => try!(lua.execute::<()>(&format!("{} = nil", name))),
+                Argument::Number(n) => lua.set(name.as_str(), n),
+                Argument::String(ref s) => lua.set(name.as_str(), s.clone()),
+                Argument::Unit(ref unit) => Self::set_unit(lua, &name, unit),
+                Argument::Value(ref value) => {
+                    try!(lua.execute::<()>(&format!("{} = {}", name, value.to_lua())))
+                }
+            }
+            names.push(name);
+        }
+
+        let result = match try!(Self::call(lua, function, &names.join(", "))) {
+            Some(result) => result,
+            None => return Ok((vec![], vec![])),
+        };
+
+        // Either a new state for the unit, a delta, a message, or a list of them. Deltas
+        // can only act on the unit itself, other units are ordered about with
+        // `command` or by their team's commander. New units belong to nobody yet,
+        // so any unit can call in reinforcements.
+        let mut deltas = vec![];
+        let mut messages = vec![];
+        for value in Self::returned(&result) {
+            if Message::is_message(value) {
+                messages.push(try!(Message::from_value(Origin::Unit(self_unit.id), value)));
+                continue;
+            }
 
-        match other_unit {
-            Some(other) => {
-                Self::set_unit(lua, "__other", &other);
-                try!(lua.execute(&format!("__result = {}(__self, __other)", function)));
+            if Delta::is_delta(value) {
+                let delta = try!(Delta::from_value(value));
+                match delta.unit() {
+                    Some(id) if id != self_unit.id => {
+                        warn!(target: "lua", "{} cannot apply {}", self_unit.id, delta.to_string())
+                    }
+                    _ => deltas.push(delta),
+                }
+                continue;
+            }
+
+            let state = try!(UnitState::from_value(value));
+            if state != self_unit.state {
+                deltas.push(Delta::UpdateState(self_unit.id, state));
             }
-            None => try!(lua.execute(&format!("__result = {}(__self)", function))),
         }
 
-        let new_state: String = match lua.get("__result") {
-            Some(state) => state,
-            None => return Ok(None),
+        Ok((deltas, messages))
+    }
+
+    // Commanders can only return deltas and messages, and only for units of their
+    // own team. They have no position, so they cannot message a radius.
+    fn exec_team_function(lua: &mut Lua,
+                          team: &TeamSnapshot)
+                          -> Result<(Vec<Delta>, Vec<Message>), Error> {
+        if try!(lua.execute::<bool>("return _G[\"team_on_tick\"] == nil")) {
+            return Ok((vec![], vec![]));
+        }
+
+        try!(lua.execute::<()>(&format!("__team = {{id = {}, units = {{}}, visible = {{}}}}",
+                                        team.team)));
+        for unit in &team.units {
+            Self::set_unit(lua, "__unit", unit);
+            try!(lua.execute::<()>("table.insert(__team.units, __unit)"));
+        }
+        for unit in &team.visible {
+            Self::set_unit(lua, "__unit", unit);
+            try!(lua.execute::<()>("table.insert(__team.visible, __unit)"));
+        }
+
+        let result = match try!(Self::call(lua, "team_on_tick", "__team")) {
+            Some(result) => result,
+            None => return Ok((vec![], vec![])),
         };
 
-        match UnitState::from_str(&new_state) {
-            Ok(state) => {
-                if state != self_unit.state {
-                    Ok(Some(Delta::UpdateState(self_unit.id, state)))
+        let mut deltas = vec![];
+        let mut messages = vec![];
+        for value in Self::returned(&result) {
+            if Message::is_message(value) {
+                let message = try!(Message::from_value(Origin::Team(team.team), value));
+                let allowed = match message.recipients {
+                    Recipients::Unit(id) => team.units.iter().any(|unit| unit.id == id),
+                    Recipients::Team(to) => to == team.team,
+                    Recipients::Radius(_) => false,
+                };
+                if allowed {
+                    messages.push(message);
                 } else {
-                    Ok(None)
+                    warn!(target: "lua",
+                          "team {} cannot message {}",
+                          team.team,
+                          message.recipients.to_string())
                 }
+                continue;
             }
-            Err(_) => panic!("Invalid state: {}", new_state),
+
+            let delta = try!(Delta::from_value(value));
+            match delta.unit() {
+                Some(id) if team.units.iter().any(|unit| unit.id == id) => deltas.push(delta),
+                _ => {
+                    warn!(target: "lua",
+                          "team {} cannot apply {}",
+                          team.team,
+                          delta.to_string())
+                }
+            }
+        }
+
+        Ok((deltas, messages))
+    }
+
+    // Handlers return one value or a list of them. Tables without a kind are lists,
+    // so an empty one returns nothing.
+    fn returned(result: &Value) -> Vec<&Value> {
+        match *result {
+            Value::Table(_) if result.get("kind").is_none() => result.array(),
+            _ => vec![result],
         }
     }
 
-    fn generate_timeline(lua: &mut Lua) -> Result<Vec<TimelineEvent>, Error> {
-        if try!(lua.execute::<bool>("return _G[\"timeline\"] == nil")) {
+    // xpcall keeps Lua errors inside the handler and hands back a traceback instead
+    fn call(lua: &mut Lua, function: &str, args: &str) -> Result<Option<Value>, Error> {
+        try!(lua.execute::<()>(&format!("__ok, __result = xpcall({}, debug.traceback, {})",
+                                        function,
+                                        args)));
+
+        if !try!(lua.execute::<bool>("return __ok")) {
+            let traceback: String = try!(lua.execute("return tostring(__result)"));
+            return Err(Error::LuaTraceback(traceback));
+        }
+
+        Ok(try!(value::read_global(lua, "__result")))
+    }
+
+    // A script that fails to load keeps whatever it defined before the error,
+    // every other global is left as it was
+    fn reload_scripts(lua: &mut Lua, paths: &[PathBuf]) {
+        for path in paths {
+            match load_lua_script(lua, path) {
+                Ok(_) => info!(target: "reload", "reloaded {}", path.display()),
+                Err(err) => {
+                    error!(target: "reload", "failed to reload {}: {}", path.display(), err)
+                }
+            }
+        }
+    }
+
+    fn generate_timeline(lua: &mut Lua, scenario: &str) -> Result<Vec<TimelineEvent>, Error> {
+        if try!(lua.execute::<bool>(&format!("return _G[\"{}\"] == nil", scenario))) {
+            warn!(target: "lua", "scenario {} is not defined, starting with no timeline", scenario);
             return Ok(vec![]);
         }
 
-        try!(lua.execute("__timeline = __flatten_timeline(timeline())"));
+        try!(lua.execute::<()>(&format!("__timeline = __flatten_timeline({}())", scenario)));
 
-        let mut timeline: LuaTable<_> = match lua.get("__timeline") {
+        let timeline = match try!(value::read_global(lua, "__timeline")) {
             Some(table) => table,
             None => return Err(Error::LuaIndexNotFound("__timeline".to_string())),
         };
 
-        let result = timeline.iter()
-            .filter_map(|e| e)
-            .map(|(_, v): (u32, String)| TimelineEvent::from_str(&v).unwrap())
-            .collect::<Vec<TimelineEvent>>();
+        let mut result = vec![];
+        for event in timeline.array() {
+            match TimelineEvent::from_value(event) {
+                Ok(event) => result.push(event),
+                Err(err) => error!(target: "lua", "skipping timeline event {:?}: {}", event, err),
+            }
+        }
         Ok(result)
     }
 
-    fn new_lua_instance<'a>() -> Lua<'a> {
+    fn new_lua_instance<'a>(config: &Config, stream: Stream) -> Lua<'a> {
         let mut lua = Lua::new();
         lua.openlibs();
 
-        lua.set("uuid", hlua::function0(gen_uuid));
-        lua.set("SCENE_WIDTH", SCENE_SIZE[0]);
-        lua.set("SCENE_HEIGHT", SCENE_SIZE[1]);
+        let random = Rc::new(RefCell::new(Random::new(config.seed, stream)));
+        let id_random = random.clone();
 
-        match load_lua_scripts(&mut lua) {
+        lua.set("uuid",
+                hlua::function0(move || id_random.borrow_mut().gen_id().hyphenated().to_string()));
+        lua.set("__random", hlua::function0(move || random.borrow_mut().gen_f64()));
+
+        // Scripts can read the scene size as they load, it is the size given on the
+        // command line or the default until the scenario has settled on its scene
+        let size = config.scene_size.unwrap_or(scene::DEFAULT_SIZE);
+        Self::set_scene(&mut lua, &Scene::new(size));
+
+        match load_lua_scripts(&mut lua, &config.script_dir) {
             Ok(_) => lua,
             Err(err) => panic!(err),
         }
     }
 
+    // Scene and roles belong to the scenario script, `<scripts>/<scenario>.lua`, so
+    // whatever the other scripts left in those globals is dropped before it runs again
+    fn load_scenario(lua: &mut Lua, config: &Config) -> Result<(), Error> {
+        try!(lua.execute::<()>("scene = nil; roles = nil"));
+        let path = Path::new(&config.script_dir).join(format!("{}.lua", config.scenario));
+        load_lua_script(lua, &path)
+    }
+
+    // The scenario's `scene` table, with the size given on the command line winning
+    fn read_scene(lua: &mut Lua, config: &Config) -> Scene {
+        let mut scene = match value::read_global(lua, "scene") {
+            Ok(Some(value)) => {
+                let mut random = Random::new(config.seed, Stream::Obstacles);
+                Scene::from_value(&value, &mut random).unwrap_or_else(|err| {
+                    error!(target: "lua", "invalid scene: {}", err);
+                    Scene::new(scene::DEFAULT_SIZE)
+                })
+            }
+            Ok(None) => Scene::new(scene::DEFAULT_SIZE),
+            Err(err) => {
+                error!(target: "lua", "invalid scene: {:?}", err);
+                Scene::new(scene::DEFAULT_SIZE)
+            }
+        };
+
+        if let Some(size) = config.scene_size {
+            scene.size = size;
+        }
+        scene
+    }
+
+    // The scenario's `roles` table, on top of the built in roles
+    fn read_roles(lua: &mut Lua) -> Vec<Role> {
+        match value::read_global(lua, "roles") {
+            Ok(Some(value)) => {
+                Roles::from_value(&value).unwrap_or_else(|err| {
+                    error!(target: "lua", "invalid roles: {}", err);
+                    vec![]
+                })
+            }
+            Ok(None) => vec![],
+            Err(err) => {
+                error!(target: "lua", "invalid roles: {:?}", err);
+                vec![]
+            }
+        }
+    }
+
+    fn set_scene(lua: &mut Lua, scene: &Scene) {
+        lua.set("SCENE_WIDTH", scene.size[0]);
+        lua.set("SCENE_HEIGHT", scene.size[1]);
+    }
+
     fn set_unit(lua: &mut Lua, index: &str, unit: &UnitSnapshot) {
         let mut table: LuaTable<_> = lua.empty_array(index);
 
@@ -308,7 +927,97 @@ impl Interpreter {
         table.set("x", unit.x);
         table.set("y", unit.y);
         table.set("team", unit.team as u32);
-        table.set("role", unit.role.to_string());
+        table.set("role", unit.role.clone());
         table.set("state", unit.state.to_string());
+        table.set("health", unit.health);
+        table.set("ammo", unit.ammo as u32);
+        table.set("reserve", unit.reserve as u32);
+
+        let mut orders: LuaTable<_> = table.empty_array("orders");
+        for (i, order) in unit.orders.iter().enumerate() {
+            orders.set(i as u32 + 1, order.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hlua::Lua;
+
+    use super::{Delta, Error, Interpreter, Message, Origin, UnitSnapshot};
+    use unit::{Id, UnitState};
+
+    fn snapshot(id: Id) -> UnitSnapshot {
+        UnitSnapshot {
+            id: id,
+            x: 0.0,
+            y: 0.0,
+            team: 1,
+            role: "soldier".to_string(),
+            state: UnitState::Idle,
+            health: 100.0,
+            ammo: 0,
+            reserve: 0,
+            orders: vec![],
+        }
+    }
+
+    fn exec(handler: &str, unit: &UnitSnapshot) -> Result<(Vec<Delta>, Vec<Message>), Error> {
+        let mut lua = Lua::new();
+        lua.openlibs();
+        lua.execute::<()>(include_str!("../lua/core.lua")).unwrap();
+        lua.execute::<()>(handler).unwrap();
+        Interpreter::exec_function(&mut lua, "soldier_on_spawn", unit, &[])
+    }
+
+    #[test]
+    fn drops_deltas_for_other_units() {
+        let unit = snapshot(Id::new_v4());
+        let handler = format!("function soldier_on_spawn (self)
+                                  return {{update_state(\"{}\", \"dead\"),
+                                          enqueue(self.id, \"dead\")}}
+                               end",
+                              Id::new_v4());
+
+        let (deltas, _) = exec(&handler, &unit).unwrap();
+        assert_eq!(deltas.len(), 1);
+        assert_eq!(deltas[0].unit(), Some(unit.id));
+    }
+
+    #[test]
+    fn accepts_deltas_for_itself() {
+        let unit = snapshot(Id::new_v4());
+        let handler = "function soldier_on_spawn (self)
+                          return {set_fov(self.id, 0.3, 600), enqueue(self.id, \"dead\")}
+                       end";
+
+        assert_eq!(exec(handler, &unit).unwrap().0.len(), 2);
+    }
+
+    #[test]
+    fn returns_nothing_for_an_empty_list() {
+        let unit = snapshot(Id::new_v4());
+        let handler = "function soldier_on_spawn (self)
+                          return {}
+                       end";
+
+        let (deltas, messages) = exec(handler, &unit).unwrap();
+        assert!(deltas.is_empty());
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn stamps_messages_with_their_sender() {
+        let unit = snapshot(Id::new_v4());
+        let handler = format!("function soldier_on_spawn (self)
+                                  return {{kind = \"message\", sender = \"{}\", team = 2}}
+                               end",
+                              Id::new_v4());
+        let (_, messages) = exec(&handler, &unit).unwrap();
+
+        match messages[0].origin {
+            Origin::Unit(id) => assert_eq!(id, unit.id),
+            origin => panic!("expected the unit as sender, got {:?}", origin),
+        }
     }
 }
@@ -5,77 +5,201 @@
 extern crate env_logger;
 extern crate hlua;
 #[macro_use]
-extern crate lazy_static;
-#[macro_use]
 extern crate log;
 extern crate nalgebra;
 extern crate ncollide;
 extern crate piston_window;
-extern crate regex;
+extern crate rand;
 extern crate time;
 extern crate uuid;
 
+mod config;
 mod geometry;
+mod grid;
+mod headless;
 mod interpreter;
+mod navigation;
+mod obstacle;
 mod parser;
+mod random;
+mod replay;
+mod role;
+mod scene;
 mod unit;
+mod value;
+mod watcher;
+mod weapon;
 
+use env_logger::LogBuilder;
+use nalgebra::Point2;
 use piston_window::*;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env;
 use std::f64;
-use std::sync::mpsc::{self, Receiver, TryRecvError};
-
-use interpreter::{Delta, Error, EventType, Interpreter};
-use unit::{GREEN, Id, Ids, Unit, UnitState, Views};
+use std::io::{self, Write};
+use std::process;
+use std::sync::mpsc::{self, Receiver, RecvError, TryRecvError};
+
+use config::Config;
+use grid::Grid;
+use interpreter::{Argument, Delta, Error, EventType, Interpreter, Message, Origin, Recipients,
+                  TeamSnapshot, UnitSnapshot};
+use navigation::Navigation;
+use random::{Random, Seed, Stream};
+use replay::{Recorder, Replay};
+use role::Roles;
+use scene::Scene;
+use unit::{GREEN, Id, Ids, SightRay, Snapshot, Unit, UnitState};
+use value::{Key, Value};
+use weapon::WeaponEvent;
 
 const BILLION: u64 = 1000000000;
+const GRID_CELL_SIZE: f64 = 100.0;
+const NAV_CELL_SIZE: f64 = 20.0;
+const TEAM_TICK_INTERVAL: f64 = 1.0;
 
 struct State {
-    interpreter: Interpreter,
-    units: HashMap<Id, Unit>,
-    collision_cache: HashMap<Id, Ids>,
-    view_cache: HashMap<Id, Ids>,
+    interpreter: Option<Interpreter>,
+    units: BTreeMap<Id, Unit>,
+    grid: Grid,
+    collision_cache: BTreeMap<Id, Ids>,
+    view_cache: BTreeMap<Id, Ids>,
+    obstacle_contacts: BTreeMap<Id, Ids>,
+    // Who fired the bullet that killed a unit, until the unit is removed
+    killers: BTreeMap<Id, Id>,
+    navigation: Navigation,
     delta_rx: Receiver<Delta>,
+    message_rx: Receiver<Message>,
+    random: Random,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+    seed: Seed,
+    scene: Scene,
+    roles: Roles,
+    tick: usize,
+    elapsed: f64,
+    team_tick_time: f64,
 }
 
 impl State {
-    fn new() -> State {
+    fn new(config: &Config) -> Result<State, Error> {
         let (tx, rx) = mpsc::channel();
+        let (message_tx, message_rx) = mpsc::channel();
+        let interpreter = Interpreter::new(tx, message_tx, config);
+        let scene = interpreter.scene.clone();
+        let roles = interpreter.roles.clone();
+
+        let recorder = match config.record {
+            Some(ref path) => Some(try!(Recorder::create(path, config.seed, &scene, &roles))),
+            None => None,
+        };
+
+        Ok(State {
+            interpreter: Some(interpreter),
+            units: BTreeMap::new(),
+            grid: Grid::new(GRID_CELL_SIZE),
+            collision_cache: BTreeMap::new(),
+            view_cache: BTreeMap::new(),
+            obstacle_contacts: BTreeMap::new(),
+            killers: BTreeMap::new(),
+            navigation: Navigation::new(&scene, NAV_CELL_SIZE),
+            delta_rx: rx,
+            message_rx: message_rx,
+            random: Random::new(config.seed, Stream::State),
+            recorder: recorder,
+            replay: None,
+            seed: config.seed,
+            scene: scene,
+            roles: roles,
+            tick: 0,
+            elapsed: 0.0,
+            team_tick_time: 0.0,
+        })
+    }
+
+    // Rebuilds a recorded battle from its deltas alone, without loading any Lua
+    fn from_replay(replay: Replay) -> State {
+        let (_, rx) = mpsc::channel();
+        let (_, message_rx) = mpsc::channel();
         State {
-            interpreter: Interpreter::new(tx),
-            units: HashMap::new(),
-            collision_cache: HashMap::new(),
-            view_cache: HashMap::new(),
+            interpreter: None,
+            units: BTreeMap::new(),
+            grid: Grid::new(GRID_CELL_SIZE),
+            collision_cache: BTreeMap::new(),
+            view_cache: BTreeMap::new(),
+            obstacle_contacts: BTreeMap::new(),
+            killers: BTreeMap::new(),
+            navigation: Navigation::new(&replay.scene, NAV_CELL_SIZE),
             delta_rx: rx,
+            message_rx: message_rx,
+            random: Random::new(replay.seed, Stream::State),
+            recorder: None,
+            seed: replay.seed,
+            scene: replay.scene.clone(),
+            roles: Roles::new(replay.roles.clone()),
+            replay: Some(replay),
+            tick: 0,
+            elapsed: 0.0,
+            team_tick_time: 0.0,
         }
     }
 
-    fn add_unit(&mut self, unit: Unit) {
-        self.collision_cache.insert(unit.id, HashSet::new());
-        self.view_cache.insert(unit.id, HashSet::new());
-        self.units.insert(unit.id, unit);
+    fn add_unit(&mut self, unit: Unit) -> Result<(), Error> {
+        let id = unit.id;
+        self.collision_cache.insert(id, BTreeSet::new());
+        self.view_cache.insert(id, BTreeSet::new());
+        self.grid.update(id, &unit.bounds());
+        self.units.insert(id, unit);
+
+        if let Some(ref mut interpreter) = self.interpreter {
+            let unit = self.units.get(&id).unwrap();
+            try!(interpreter.exec(&unit.role, &EventType::Spawn, unit, None));
+        }
+        Ok(())
     }
 
     fn update(&mut self, args: &UpdateArgs) -> Result<(), Error> {
         let time_start = time::precise_time_ns();
         let mut changed = vec![];
 
-        try!(self.run_all_unit_updates(args));
+        self.tick += 1;
+
+        let (args, spawns, replayed) = match self.replay {
+            Some(ref mut replay) => {
+                let (dt, spawns, deltas) = replay.next_tick(self.tick);
+                (UpdateArgs { dt: dt }, spawns, Some(deltas))
+            }
+            None => (*args, vec![], None),
+        };
+
+        if let Some(ref mut recorder) = self.recorder {
+            try!(recorder.record_dt(self.tick, args.dt));
+        }
+
+        self.elapsed += args.dt;
+
+        try!(self.run_all_unit_updates(&args, spawns));
+        try!(self.run_all_damage());
         try!(self.run_all_collisions());
         try!(self.run_all_views());
+        try!(self.run_all_ticks(&args));
+        try!(self.run_team_ticks(&args));
 
-        loop {
-            match self.delta_rx.try_recv() {
-                Ok(delta) => {
-                    if let Some(id) = self.apply_delta(delta) {
-                        changed.push(id)
-                    }
-                }
-                Err(TryRecvError::Empty) => break,
-                Err(TryRecvError::Disconnected) => panic!("delta_rx disconnected"),
+        let deltas = match replayed {
+            Some(deltas) => deltas,
+            None => try!(self.receive_deltas()),
+        };
+
+        for delta in deltas {
+            if let Some(id) = try!(self.apply_delta(delta)) {
+                changed.push(id)
             }
         }
 
+        for message in try!(self.receive_messages()) {
+            try!(self.deliver(message));
+        }
+
         let dead_units = self.units
             .iter()
             .filter(|&(_, u)| {
@@ -87,13 +211,31 @@ impl State {
             .map(|(k, _)| *k)
             .collect::<Ids>();
 
+        // Every dead unit hears of its death before any of them is removed, so
+        // that a killer who died in the same tick can still be handed over
+        if let Some(ref mut interpreter) = self.interpreter {
+            let units = &self.units;
+            for dead_unit in &dead_units {
+                let unit = units.get(dead_unit).unwrap();
+                let killer = self.killers
+                    .get(dead_unit)
+                    .and_then(|id| units.get(id))
+                    .map(UnitSnapshot::new);
+                try!(interpreter.exec(&unit.role, &EventType::Death, unit, killer));
+            }
+        }
+
         for dead_unit in dead_units {
+            self.grid.remove(&dead_unit);
             self.units.remove(&dead_unit);
         }
+        self.killers.clear();
 
-        for id in changed {
-            if let Some(unit) = self.units.get(&id) {
-                try!(self.interpreter.exec(&unit.role, &EventType::StateChange, unit, None))
+        if let Some(ref mut interpreter) = self.interpreter {
+            for id in changed {
+                if let Some(unit) = self.units.get(&id) {
+                    try!(interpreter.exec(&unit.role, &EventType::StateChange, unit, None))
+                }
             }
         }
 
@@ -107,7 +249,7 @@ impl State {
         if time_start % 1000 == 0 {
             info!(target: "units", "---");
             for unit in self.units.values() {
-                info!(target: "units", "{} {:?} {:?}", unit.id, unit.role, unit.state);
+                info!(target: "units", "{} {} {:?}", unit.id, unit.role.name, unit.state);
             }
             info!(target: "units", "---");
         }
@@ -115,37 +257,113 @@ impl State {
         Ok(())
     }
 
-    fn run_all_unit_updates(&mut self, args: &UpdateArgs) -> Result<(), Error> {
+    fn receive_deltas(&mut self) -> Result<Vec<Delta>, Error> {
+        let interpreter = match self.interpreter {
+            Some(ref mut interpreter) => interpreter,
+            None => return Ok(vec![]),
+        };
+
+        try!(interpreter.sync());
+        try!(interpreter.reload_changed_scripts());
+
+        let mut deltas = interpreter.timeline_deltas(self.elapsed);
+
+        loop {
+            match self.delta_rx.try_recv() {
+                Ok(delta) => deltas.push(delta),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    return Err(Error::InterpreterStopped(RecvError))
+                }
+            }
+        }
+
+        Ok(deltas)
+    }
+
+    // Messages sent by the handlers that ran up to the last sync
+    fn receive_messages(&mut self) -> Result<Vec<Message>, Error> {
+        let mut messages = vec![];
+        if self.interpreter.is_none() {
+            return Ok(messages);
+        }
+
+        loop {
+            match self.message_rx.try_recv() {
+                Ok(message) => messages.push(message),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    return Err(Error::InterpreterStopped(RecvError))
+                }
+            }
+        }
+
+        Ok(messages)
+    }
+
+    fn run_all_unit_updates(&mut self,
+                            args: &UpdateArgs,
+                            spawns: Vec<Delta>)
+                            -> Result<(), Error> {
         let mut changed = HashSet::new();
         let mut commands = HashMap::new();
         let mut new_units = vec![];
+        let mut weapon_events = vec![];
+        let mut commanded = vec![];
 
-        let views = self.units
-            .values()
-            .map(|u| {
-                let map = self.units
-                    .keys()
-                    .map(|id| {
-                        let unit = self.units.get(id).unwrap();
-                        (*id, (unit.pose, unit.shape.clone()))
-                    })
-                    .collect::<Views>();
-                (u.id, map)
-            })
-            .collect::<HashMap<Id, Views>>();
+        let snapshot = self.units
+            .iter()
+            .map(|(id, unit)| (*id, (unit.pose, unit.shape.clone())))
+            .collect::<Snapshot>();
+
+        self.obstacle_contacts.clear();
+
+        // Units that cannot reach their destination are told so, and then give up on it
+        for unit in self.units.values_mut() {
+            if unit.find_path(&mut self.navigation, &self.scene) {
+                continue;
+            }
+
+            if let Some(ref mut interpreter) = self.interpreter {
+                try!(interpreter.exec(&unit.role, &EventType::Unreachable, unit, None));
+            }
+            info!(target: "units", "{} {:?} unreachable", unit.role.name, unit.state);
+            unit.next_state();
+            changed.insert(unit.id);
+        }
 
         for unit in self.units.values_mut() {
             let original_state = unit.state.clone();
-            let view = views.get(&unit.id).unwrap();
+            let previous_pose = unit.pose;
+
+            let update_results = unit.update(args, &snapshot, &self.roles, &mut self.random);
+            self.scene.enforce(unit);
 
-            let update_results = unit.update(args, view);
+            // Projectiles are spent on the first obstacle they touch, anything
+            // else slides along it
+            let hit = if unit.role.projectile {
+                let hit = self.scene.obstacles_hit(&unit.pose, &unit.shape);
+                if !hit.is_empty() {
+                    unit.state = UnitState::Dead;
+                }
+                hit
+            } else {
+                self.scene.block(unit, &previous_pose)
+            };
+            if !hit.is_empty() {
+                self.obstacle_contacts.insert(unit.id, hit);
+            }
+            self.grid.update(unit.id, &unit.bounds());
 
             if let Some((id, state)) = update_results.command {
-                commands.insert(id, state);
+                commands.insert(id, (unit.id, state));
             }
             if let Some(unit) = update_results.unit {
                 new_units.push(unit)
             }
+            if let Some(event) = update_results.event {
+                weapon_events.push((unit.id, event));
+            }
 
             if unit.state != original_state {
                 changed.insert(unit.id);
@@ -153,19 +371,100 @@ impl State {
         }
 
         for unit in self.units.values_mut() {
-            if let Some(state) = commands.remove(&unit.id) {
-                unit.state = state;
+            if let Some((issuer, state)) = commands.remove(&unit.id) {
+                unit.set_state(state);
                 changed.insert(unit.id);
+                commanded.push((unit.id, issuer));
+            }
+        }
+
+        // Replays spawn their bullets from the recorded spawns instead, at the same
+        // point of the tick so that they can hit something straight away
+        if self.replay.is_none() {
+            for unit in new_units.into_iter() {
+                try!(self.record_spawn(&unit));
+                try!(self.add_unit(unit));
+            }
+        }
+        for delta in spawns {
+            try!(self.apply_delta(delta));
+        }
+
+        if let Some(ref mut interpreter) = self.interpreter {
+            for (id, event) in weapon_events {
+                let event_type = match event {
+                    WeaponEvent::Reload => EventType::Reload,
+                    WeaponEvent::OutOfAmmo => EventType::OutOfAmmo,
+                };
+                let unit = self.units.get(&id).unwrap();
+                try!(interpreter.exec(&unit.role, &event_type, unit, None));
+            }
+
+            for (id, issuer) in commanded {
+                let unit = self.units.get(&id).unwrap();
+                let issuer = self.units
+                    .get(&issuer)
+                    .map_or(Argument::Nil, |issuer| Argument::Unit(UnitSnapshot::new(issuer)));
+                let args = vec![issuer, Argument::String(unit.state.to_string())];
+                try!(interpreter.exec_args(&unit.role, &EventType::Command, unit, args));
+            }
+
+            for unit in self.units.values() {
+                if changed.contains(&unit.id) {
+                    try!(interpreter.exec(&unit.role, &EventType::StateChange, unit, None));
+                }
             }
         }
 
-        for unit in new_units.into_iter() {
-            self.add_unit(unit)
+        Ok(())
+    }
+
+    // Every bullet hurts the first unit it touches and is spent
+    fn run_all_damage(&mut self) -> Result<(), Error> {
+        let mut hits = vec![];
+
+        for bullet in self.units.values() {
+            if !bullet.role.projectile || bullet.state == UnitState::Dead {
+                continue;
+            }
+
+            // A bullet never hits whoever fired it
+            let target = Self::detect_collisions(&self.units, &self.grid, bullet)
+                .into_iter()
+                .find(|id| !self.units[id].role.projectile && Some(*id) != bullet.owner);
+            if let Some(target) = target {
+                hits.push((bullet.id, target));
+            }
         }
 
-        for unit in self.units.values() {
-            if changed.contains(&unit.id) {
-                try!(self.interpreter.exec(&unit.role, &EventType::StateChange, unit, None));
+        // A unit killed earlier in the tick still stops the bullet, whichever
+        // order the hits come in
+        for (bullet_id, target_id) in hits {
+            let (bullet, damage, owner) = {
+                let bullet = self.units.get_mut(&bullet_id).unwrap();
+                bullet.state = UnitState::Dead;
+                (UnitSnapshot::new(bullet), bullet.damage, bullet.owner)
+            };
+
+            if self.units[&target_id].state == UnitState::Dead {
+                continue;
+            }
+
+            let target = self.units.get_mut(&target_id).unwrap();
+            let killed = target.take_damage(damage);
+            info!(target: "units",
+                  "{} {} hit for {:?}, {:?} left",
+                  target.role.name,
+                  target.id,
+                  damage,
+                  target.health);
+
+            if killed {
+                if let Some(owner) = owner {
+                    self.killers.insert(target_id, owner);
+                }
+            } else if let Some(ref mut interpreter) = self.interpreter {
+                try!(interpreter.exec(&target.role, &EventType::Damaged, target, Some(bullet)));
             }
         }
 
@@ -173,14 +472,23 @@ impl State {
     }
 
     fn run_all_collisions(&mut self) -> Result<(), Error> {
+        let interpreter = match self.interpreter {
+            Some(ref mut interpreter) => interpreter,
+            None => return Ok(()),
+        };
         let units = &self.units;
 
         for id in units.keys() {
             let unit = self.units.get(id).unwrap();
             let seen = self.collision_cache.remove(id).unwrap();
-            let current_view =
-                try!(Self::run_collisions(&mut self.interpreter, unit, &seen, units));
-            self.collision_cache.insert(*id, current_view);
+
+            let mut current = Self::detect_collisions(units, &self.grid, unit);
+            if let Some(hit) = self.obstacle_contacts.get(id) {
+                current.extend(hit.iter().cloned());
+            }
+
+            try!(Self::run_collisions(interpreter, unit, &seen, &current, units, &self.scene));
+            self.collision_cache.insert(*id, current);
         }
 
         Ok(())
@@ -189,26 +497,42 @@ impl State {
     fn run_collisions(interp: &mut Interpreter,
                       unit: &Unit,
                       collisions: &Ids,
-                      units: &HashMap<Id, Unit>)
-                      -> Result<Ids, Error> {
-        let current_collisions = Self::detect_collisions(units, unit);
-
-        for collision_id in &current_collisions {
+                      current_collisions: &Ids,
+                      units: &BTreeMap<Id, Unit>,
+                      scene: &Scene)
+                      -> Result<(), Error> {
+        for collision_id in current_collisions {
             if !collisions.contains(collision_id) {
-                let collision = units.get(collision_id).unwrap();
+                let collision = match units.get(collision_id) {
+                    Some(other) => UnitSnapshot::new(other),
+                    None => UnitSnapshot::obstacle(scene.obstacle(collision_id).unwrap()),
+                };
                 try!(interp.exec(&unit.role, &EventType::Collision, unit, Some(collision)))
             }
         }
-        Ok(current_collisions)
+
+        for collision_id in collisions.difference(current_collisions) {
+            let collision = match units.get(collision_id) {
+                Some(other) => Some(UnitSnapshot::new(other)),
+                None => scene.obstacle(collision_id).map(UnitSnapshot::obstacle),
+            };
+            try!(interp.exec(&unit.role, &EventType::ExitCollision, unit, collision))
+        }
+        Ok(())
     }
 
     fn run_all_views(&mut self) -> Result<(), Error> {
+        let interpreter = match self.interpreter {
+            Some(ref mut interpreter) => interpreter,
+            None => return Ok(()),
+        };
         let units = &self.units;
 
         for id in units.keys() {
             let unit = self.units.get(id).unwrap();
             let seen = self.view_cache.remove(id).unwrap();
-            let current_view = try!(Self::run_views(&mut self.interpreter, unit, &seen, units));
+            let current_view =
+                try!(Self::run_views(interpreter, unit, &seen, units, &self.grid, &self.scene));
             self.view_cache.insert(*id, current_view);
         }
 
@@ -218,52 +542,172 @@ impl State {
     fn run_views(interp: &mut Interpreter,
                  unit: &Unit,
                  seen: &Ids,
-                 units: &HashMap<Id, Unit>)
+                 units: &BTreeMap<Id, Unit>,
+                 grid: &Grid,
+                 scene: &Scene)
                  -> Result<Ids, Error> {
-        let current_views = Self::detect_views(units, unit);
+        let current_views = Self::detect_views(units, grid, scene, unit);
 
         for view_id in &current_views {
             if !seen.contains(view_id) {
                 let other = units.get(view_id).unwrap();
-                try!(interp.exec(&unit.role, &EventType::EnterView, unit, Some(other)))
+                try!(interp.exec(&unit.role,
+                                 &EventType::EnterView,
+                                 unit,
+                                 Some(UnitSnapshot::new(other))))
             }
         }
 
         let not_seen = seen.difference(&current_views).cloned().collect::<Ids>();
 
         for view_id in not_seen {
-            let other = units.get(&view_id);
+            let other = units.get(&view_id).map(UnitSnapshot::new);
             try!(interp.exec(&unit.role, &EventType::ExitView, unit, other))
         }
 
         Ok(current_views)
     }
 
-    fn detect_collisions(units: &HashMap<Id, Unit>, unit: &Unit) -> Ids {
-        units.iter()
-            .filter(|&(id, _)| &unit.id != id)
-            .filter(|&(_, u)| unit.overlaps(u))
-            .map(|(collide_id, _)| *collide_id)
+    fn run_all_ticks(&mut self, args: &UpdateArgs) -> Result<(), Error> {
+        let interpreter = match self.interpreter {
+            Some(ref mut interpreter) => interpreter,
+            None => return Ok(()),
+        };
+
+        for unit in self.units.values_mut() {
+            if unit.state == UnitState::Dead {
+                continue;
+            }
+            if let Some(dt) = unit.tick(args.dt) {
+                try!(interpreter.exec_args(&unit.role,
+                                           &EventType::Tick,
+                                           unit,
+                                           vec![Argument::Number(dt)]));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Every team hands `team_on_tick` what all of its units know, projectiles
+    // are left out on both sides
+    fn run_team_ticks(&mut self, args: &UpdateArgs) -> Result<(), Error> {
+        let interpreter = match self.interpreter {
+            Some(ref mut interpreter) => interpreter,
+            None => return Ok(()),
+        };
+
+        self.team_tick_time += args.dt;
+        if self.team_tick_time < TEAM_TICK_INTERVAL {
+            return Ok(());
+        }
+        // Leftover time counts towards the next tick, as it does for units
+        self.team_tick_time %= TEAM_TICK_INTERVAL;
+
+        let units = &self.units;
+        let view_cache = &self.view_cache;
+
+        let mut teams = BTreeMap::new();
+        for unit in units.values() {
+            if !unit.role.projectile && unit.state != UnitState::Dead {
+                teams.entry(unit.team).or_insert_with(Vec::new).push(unit);
+            }
+        }
+
+        for (team, members) in teams {
+            let visible = members.iter()
+                .flat_map(|unit| view_cache.get(&unit.id).into_iter().flat_map(|ids| ids))
+                .filter_map(|id| units.get(id))
+                .filter(|other| other.team != team && !other.role.projectile)
+                .map(|other| other.id)
+                .collect::<Ids>();
+
+            try!(interpreter.exec_team(TeamSnapshot {
+                team: team,
+                units: members.into_iter().map(UnitSnapshot::new).collect(),
+                visible: visible.iter().map(|id| UnitSnapshot::new(&units[id])).collect(),
+            }));
+        }
+
+        Ok(())
+    }
+
+    fn detect_collisions(units: &BTreeMap<Id, Unit>, grid: &Grid, unit: &Unit) -> Ids {
+        grid.candidates(&unit.bounds())
+            .into_iter()
+            .filter(|id| &unit.id != id)
+            .filter(|id| unit.overlaps(units.get(id).unwrap()))
             .collect()
     }
 
-    fn detect_views(units: &HashMap<Id, Unit>, unit: &Unit) -> Ids {
-        units.iter()
-            .filter(|&(id, _)| &unit.id != id)
-            .filter(|&(_, u)| unit.can_see(u))
-            .map(|(view_id, _)| *view_id)
+    fn detect_views(units: &BTreeMap<Id, Unit>, grid: &Grid, scene: &Scene, unit: &Unit) -> Ids {
+        let candidates = grid.candidates(&unit.fov_bounds());
+
+        candidates.iter()
+            .filter(|id| &unit.id != *id)
+            .filter(|id| {
+                let other = units.get(id).unwrap();
+                unit.can_see(other) &&
+                Self::in_line_of_sight(units, &candidates, scene, unit, other)
+            })
+            .cloned()
             .collect()
     }
 
-    fn apply_delta(&mut self, delta: Delta) -> Option<Id> {
-        match delta {
+    // Whether a ray from `unit` reaches any of `other`'s sight points without
+    // crossing an obstacle, or another unit when the scene lets units block views
+    fn in_line_of_sight(units: &BTreeMap<Id, Unit>,
+                        candidates: &Ids,
+                        scene: &Scene,
+                        unit: &Unit,
+                        other: &Unit)
+                        -> bool {
+        let blockers = if scene.units_block_view {
+            candidates.iter()
+                .filter(|id| **id != unit.id && **id != other.id)
+                .map(|id| units.get(id).unwrap())
+                .filter(|blocker| !blocker.role.projectile)
+                .collect::<Vec<&Unit>>()
+        } else {
+            vec![]
+        };
+
+        let origin = Point2::new(unit.pose.x, unit.pose.y);
+        other.sight_points().into_iter().any(|point| {
+            let ray = SightRay::new(origin, point - origin);
+            !scene.blocks_view(&ray) && !blockers.iter().any(|blocker| blocker.blocks_ray(&ray))
+        })
+    }
+
+    fn record_spawn(&mut self, unit: &Unit) -> Result<(), Error> {
+        if let Some(ref mut recorder) = self.recorder {
+            let (x, y) = unit.xy();
+            try!(recorder.record_spawn(self.tick,
+                                       &Delta::NewUnit(unit.role.name.clone(),
+                                                       unit.id,
+                                                       x,
+                                                       y,
+                                                       unit.pose.rotation,
+                                                       unit.team)));
+            try!(recorder.record_spawn(self.tick,
+                                       &Delta::UpdateState(unit.id, unit.state.clone())));
+        }
+        Ok(())
+    }
+
+    fn apply_delta(&mut self, delta: Delta) -> Result<Option<Id>, Error> {
+        if let Some(ref mut recorder) = self.recorder {
+            try!(recorder.record_delta(self.tick, &delta));
+        }
+
+        let changed = match delta {
             Delta::UpdateState(id, state) => {
                 match self.units.get_mut(&id) {
                     Some(unit) => {
                         if unit.state != UnitState::Dead && unit.state != state {
                             info!(target: "deltas",
-                                  "- {:?} {:?} -> {:?}", unit.role, unit.state, state);
-                            unit.state = state;
+                                  "- {} {:?} -> {:?}", unit.role.name, unit.state, state);
+                            unit.set_state(state);
                             Some(id)
                         } else {
                             None
@@ -277,31 +721,147 @@ impl State {
                 }
             }
             Delta::NewUnit(role, id, x, y, rotation, team) => {
-                self.add_unit(Unit::new(role, id, x, y, rotation, team, UnitState::Idle));
+                match self.roles.get(&role) {
+                    Some(role) => {
+                        let unit = Unit::new(role, id, x, y, rotation, team, UnitState::Idle);
+                        try!(self.add_unit(unit))
+                    }
+                    None => warn!(target: "deltas", "unknown role {}", role),
+                }
+                None
+            }
+            Delta::Enqueue(id, state) => {
+                let started = self.units.get_mut(&id).map_or(false, |unit| unit.enqueue(state));
+                if started {
+                    Some(id)
+                } else {
+                    None
+                }
+            }
+            Delta::Prepend(id, state) => {
+                let started = self.units.get_mut(&id).map_or(false, |unit| unit.prepend(state));
+                if started {
+                    Some(id)
+                } else {
+                    None
+                }
+            }
+            Delta::ClearOrders(id) => {
+                if let Some(unit) = self.units.get_mut(&id) {
+                    unit.clear_orders();
+                }
+                None
+            }
+            Delta::SetFov(id, angle, distance) => {
+                if let Some(unit) = self.units.get_mut(&id) {
+                    unit.set_fov(angle, distance);
+                }
                 None
             }
+            Delta::SetRange(id, angle, distance) => {
+                if let Some(unit) = self.units.get_mut(&id) {
+                    unit.set_range(angle, distance);
+                }
+                None
+            }
+        };
+
+        Ok(changed)
+    }
+
+    fn deliver(&mut self, message: Message) -> Result<(), Error> {
+        let interpreter = match self.interpreter {
+            Some(ref mut interpreter) => interpreter,
+            None => return Ok(()),
+        };
+        let sender = match message.origin {
+            Origin::Unit(id) => Some(id),
+            Origin::Team(_) => None,
+        };
+        let units = &self.units;
+        let from = sender.and_then(|id| units.get(&id));
+
+        let ids = match message.recipients {
+            Recipients::Unit(id) => vec![id],
+            Recipients::Team(team) => {
+                units
+                    .values()
+                    .filter(|unit| unit.team == team && !unit.role.projectile)
+                    .map(|unit| unit.id)
+                    .collect()
+            }
+            // Needs the sender to still be around to know where to reach from
+            Recipients::Radius(radius) => {
+                let (x, y) = match from {
+                    Some(from) => from.xy(),
+                    None => return Ok(()),
+                };
+                units
+                    .values()
+                    .filter(|unit| !unit.role.projectile)
+                    .filter(|unit| {
+                        let (dx, dy) = (unit.pose.x - x, unit.pose.y - y);
+                        dx * dx + dy * dy <= radius * radius
+                    })
+                    .map(|unit| unit.id)
+                    .collect()
+            }
+        };
+
+        for id in ids {
+            let unit = match units.get(&id) {
+                Some(unit) if Some(unit.id) != sender && unit.state != UnitState::Dead => unit,
+                _ => continue,
+            };
+            // A commander shows up as `{role = "team", team = <team>}`
+            let from = match message.origin {
+                Origin::Unit(_) => {
+                    from.map_or(Argument::Nil, |from| Argument::Unit(UnitSnapshot::new(from)))
+                }
+                Origin::Team(team) => Argument::Value(team_sender(team)),
+            };
+            try!(interpreter.exec_args(&unit.role,
+                                       &EventType::Message,
+                                       unit,
+                                       vec![from, Argument::Value(message.payload.clone())]));
         }
+
+        Ok(())
     }
 }
 
+fn team_sender(team: usize) -> Value {
+    let mut table = BTreeMap::new();
+    table.insert(Key::Name("role".to_string()), Value::String("team".to_string()));
+    table.insert(Key::Name("team".to_string()), Value::Number(team as f64));
+    Value::Table(table)
+}
+
 fn draw_units(window: &mut PistonWindow, event: Event, args: &RenderArgs, state: &State) {
+    let (width, height) = (state.scene.width(), state.scene.height());
+
     window.draw_2d(&event, |c, g| {
         clear(GREEN, g);
+
+        // Stretch the scene over the whole window, with its origin in the bottom left
+        let c = c.scale(args.width as f64 / width, args.height as f64 / height)
+            .trans(0.0, height);
+        for obstacle in &state.scene.obstacles {
+            obstacle.render(&c, g)
+        }
         for unit in state.units.values() {
             unit.render(args, &c, g)
         }
     });
 }
 
-fn main() {
-    env_logger::init().unwrap();
-    let mut window: PistonWindow = WindowSettings::new("example", geometry::SCENE_SIZE)
+fn run_window(mut state: State, config: &Config) {
+    let size = config.window_size.unwrap_or(state.scene.size);
+    let mut window: PistonWindow = WindowSettings::new("generals", size)
         .exit_on_esc(true)
         .build()
         .unwrap();
 
-    let mut state = State::new();
-
     while let Some(e) = window.next() {
         match e {
             Event::Render(args) => {
@@ -317,3 +877,135 @@ fn main() {
         }
     }
 }
+
+fn init_logger(config: &Config) {
+    let mut builder = LogBuilder::new();
+
+    if let Some(filters) = config.log_filters.clone().or_else(|| env::var("RUST_LOG").ok()) {
+        builder.parse(&filters);
+    }
+
+    builder.init().unwrap();
+}
+
+fn main() {
+    let config = match Config::from_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(message) => {
+            writeln!(io::stderr(), "{}\n\n{}", message, config::USAGE).unwrap();
+            process::exit(2);
+        }
+    };
+
+    init_logger(&config);
+
+    let state = match config.replay {
+        Some(ref path) => {
+            match Replay::open(path) {
+                Ok(replay) => State::from_replay(replay),
+                Err(err) => panic!(err),
+            }
+        }
+        None => {
+            match State::new(&config) {
+                Ok(state) => state,
+                Err(err) => panic!(err),
+            }
+        }
+    };
+
+    info!(target: "seed", "{}", state.seed);
+
+    match config.headless {
+        Some(duration) => {
+            match headless::run(state, duration, headless::DEFAULT_DT) {
+                Ok(_) => {}
+                Err(err) => panic!(err),
+            }
+        }
+        None => run_window(state, &config),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use piston_window::UpdateArgs;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use State;
+    use config::Config;
+    use replay::Replay;
+    use unit::Id;
+
+    // The target stands close enough that bullets spawn touching it
+    const DUEL: &'static str = "
+function duel ()
+   local shooter = uuid()
+   local target = uuid()
+   return {
+      [0] = {new_soldier(shooter, 100, 100, 0, 1), new_soldier(target, 135, 100, 3.14, 2)},
+      [1] = {update_state(shooter, shoot(target))}
+   }
+end
+";
+
+    fn write_script(dir: &str, name: &str, script: &str) {
+        let mut file = File::create(format!("{}/{}", dir, name)).unwrap();
+        file.write_all(script.as_bytes()).unwrap();
+    }
+
+    fn run(mut state: State, ticks: usize) -> Vec<Vec<f64>> {
+        let args = UpdateArgs { dt: 1.0 / 60.0 };
+        (0..ticks)
+            .map(|_| {
+                state.update(&args).unwrap();
+                state.units.values().filter(|u| !u.role.projectile).map(|u| u.health).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replay_matches_recorded_health() {
+        let dir = env::temp_dir().join("generals-replay-test");
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+        write_script(&dir, "core.lua", include_str!("../lua/core.lua"));
+        write_script(&dir, "bullet.lua", include_str!("../lua/bullet.lua"));
+        write_script(&dir, "duel.lua", DUEL);
+        let path = format!("{}/duel.replay", dir);
+
+        let args = vec!["--scripts", &dir, "--scenario", "duel", "--seed", "1", "--record", &path];
+        let config = Config::from_args(args.into_iter().map(String::from)).unwrap();
+        let recorded = run(State::new(&config).unwrap(), 120);
+        let replayed = run(State::from_replay(Replay::open(&path).unwrap()), 120);
+
+        assert!(recorded.iter().any(|healths| healths.iter().any(|&health| health < 100.0)));
+        assert_eq!(recorded, replayed);
+    }
+
+    #[test]
+    fn corpses_stop_bullets() {
+        let dir = env::temp_dir().join("generals-corpse-test");
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_string();
+
+        // Three bullets land on a soldier in the same tick, the second one kills it
+        let mut replay = "seed 1\nscene 800 800\n1 dt 0.016666666666666666\n".to_string();
+        replay.push_str(&format!("1 spawn new_unit(soldier, {}, 100.0, 100.0, 0.0, 2)\n",
+                                 Id::new_v4()));
+        for _ in 0..3 {
+            let id = Id::new_v4();
+            replay.push_str(&format!("1 spawn new_unit(bullet, {}, 100.0, 100.0, 0.0, 1)\n", id));
+            replay.push_str(&format!("1 spawn update_state({}, move(500.0, 100.0))\n", id));
+        }
+        write_script(&dir, "corpse.replay", &replay);
+
+        let path = format!("{}/corpse.replay", dir);
+        let mut state = State::from_replay(Replay::open(&path).unwrap());
+        state.update(&UpdateArgs { dt: 1.0 / 60.0 }).unwrap();
+
+        assert!(state.units.is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+use nalgebra::Vector2;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap};
+use std::f64;
+
+use geometry::Pose;
+use scene::Scene;
+use unit::UnitShape;
+
+pub type Waypoint = (f64, f64);
+
+type Cell = (usize, usize);
+
+const STRAIGHT_COST: usize = 10;
+const DIAGONAL_COST: usize = 14;
+
+// Cells waiting to be expanded by A*, ordered so that the cheapest one is at the
+// top of the max-heap and ties are broken on the cell to stay deterministic
+#[derive(PartialEq, Eq)]
+struct Open {
+    estimate: usize,
+    cell: Cell,
+}
+
+impl Ord for Open {
+    fn cmp(&self, other: &Open) -> Ordering {
+        match other.estimate.cmp(&self.estimate) {
+            Ordering::Equal => other.cell.cmp(&self.cell),
+            ordering => ordering,
+        }
+    }
+}
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Open) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// A* over a grid of square cells laid over the scene. A cell is blocked for a
+// unit when the unit, at any rotation, would overlap an obstacle standing at its
+// centre. Blocked cells are worked out once per unit size.
+pub struct Navigation {
+    cell_size: f64,
+    columns: usize,
+    rows: usize,
+    blocked: BTreeMap<u64, Vec<bool>>,
+}
+
+impl Navigation {
+    pub fn new(scene: &Scene, cell_size: f64) -> Navigation {
+        Navigation {
+            cell_size: cell_size,
+            columns: (scene.width() / cell_size).ceil() as usize,
+            rows: (scene.height() / cell_size).ceil() as usize,
+            blocked: BTreeMap::new(),
+        }
+    }
+
+    // Waypoints leading from `from` to `to`, ending on `to`, or None when no
+    // path gets there
+    pub fn find_path(&mut self,
+                     scene: &Scene,
+                     shape: &UnitShape,
+                     from: Waypoint,
+                     to: Waypoint)
+                     -> Option<Vec<Waypoint>> {
+        if scene.obstacles.is_empty() {
+            return Some(vec![to]);
+        }
+
+        let radius = shape.half_extents().x * f64::consts::SQRT_2;
+        let key = (radius * 1000.0) as u64;
+        if !self.blocked.contains_key(&key) {
+            let blocked = self.blocked_cells(scene, radius);
+            self.blocked.insert(key, blocked);
+        }
+        let blocked = &self.blocked[&key];
+
+        let goal = self.cell(to);
+        if blocked[self.index(goal)] {
+            return None;
+        }
+        if self.walkable(blocked, from, to) {
+            return Some(vec![to]);
+        }
+
+        let mut path = match self.search(blocked, self.cell(from), goal) {
+            Some(cells) => cells.into_iter().map(|cell| self.centre(cell)).collect::<Vec<_>>(),
+            None => return None,
+        };
+        path.pop();
+        path.push(to);
+
+        Some(self.smooth(blocked, from, &path))
+    }
+
+    fn blocked_cells(&self, scene: &Scene, radius: f64) -> Vec<bool> {
+        let shape = UnitShape::new(Vector2::new(radius, radius));
+        let mut blocked = vec![false; self.columns * self.rows];
+
+        for row in 0..self.rows {
+            for column in 0..self.columns {
+                let (x, y) = self.centre((column, row));
+                let hit = scene.obstacles_hit(&Pose::new(x, y, 0.0), &shape);
+                blocked[self.index((column, row))] = !hit.is_empty();
+            }
+        }
+
+        blocked
+    }
+
+    fn search(&self, blocked: &[bool], start: Cell, goal: Cell) -> Option<Vec<Cell>> {
+        let mut open = BinaryHeap::new();
+        let mut costs = BTreeMap::new();
+        let mut came_from = BTreeMap::new();
+
+        costs.insert(start, 0);
+        open.push(Open {
+            estimate: self.heuristic(start, goal),
+            cell: start,
+        });
+
+        while let Some(Open { cell, .. }) = open.pop() {
+            if cell == goal {
+                let mut path = vec![cell];
+                while let Some(previous) = came_from.get(path.last().unwrap()) {
+                    path.push(*previous);
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            let cost = costs[&cell];
+            for (neighbour, step) in self.neighbours(blocked, cell) {
+                let next_cost = cost + step;
+                if costs.get(&neighbour).map_or(true, |c| next_cost < *c) {
+                    costs.insert(neighbour, next_cost);
+                    came_from.insert(neighbour, cell);
+                    open.push(Open {
+                        estimate: next_cost + self.heuristic(neighbour, goal),
+                        cell: neighbour,
+                    });
+                }
+            }
+        }
+
+        None
+    }
+
+    // Diagonal steps are only taken when both cells they cut past are free, so
+    // that paths never clip the corner of an obstacle
+    fn neighbours(&self, blocked: &[bool], cell: Cell) -> Vec<(Cell, usize)> {
+        let free = |column: i64, row: i64| {
+            column >= 0 && row >= 0 && (column as usize) < self.columns &&
+            (row as usize) < self.rows &&
+            !blocked[self.index((column as usize, row as usize))]
+        };
+
+        let (column, row) = (cell.0 as i64, cell.1 as i64);
+        let mut neighbours = vec![];
+
+        for &(dx, dy) in &[(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+            if !free(column + dx, row + dy) {
+                continue;
+            }
+
+            let next = ((column + dx) as usize, (row + dy) as usize);
+            if dx == 0 || dy == 0 {
+                neighbours.push((next, STRAIGHT_COST));
+            } else if free(column + dx, row) && free(column, row + dy) {
+                neighbours.push((next, DIAGONAL_COST));
+            }
+        }
+
+        neighbours
+    }
+
+    fn heuristic(&self, from: Cell, to: Cell) -> usize {
+        let dx = (from.0 as i64 - to.0 as i64).abs() as usize;
+        let dy = (from.1 as i64 - to.1 as i64).abs() as usize;
+        STRAIGHT_COST * (dx + dy) - (2 * STRAIGHT_COST - DIAGONAL_COST) * dx.min(dy)
+    }
+
+    // Drops every waypoint that can be skipped by walking straight to a later one
+    fn smooth(&self, blocked: &[bool], from: Waypoint, path: &[Waypoint]) -> Vec<Waypoint> {
+        let mut smoothed = vec![];
+        let mut current = from;
+        let mut i = 0;
+
+        while i < path.len() {
+            let mut furthest = i;
+            for j in (i + 1..path.len()).rev() {
+                if self.walkable(blocked, current, path[j]) {
+                    furthest = j;
+                    break;
+                }
+            }
+
+            current = path[furthest];
+            smoothed.push(current);
+            i = furthest + 1;
+        }
+
+        smoothed
+    }
+
+    // Samples the segment every half cell, the start cell is never checked so
+    // that a unit brushing against an obstacle can still walk away from it
+    fn walkable(&self, blocked: &[bool], from: Waypoint, to: Waypoint) -> bool {
+        let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+        let steps = ((dx * dx + dy * dy).sqrt() / (self.cell_size / 2.0)).ceil() as usize;
+        let start = self.cell(from);
+
+        (1..steps + 1).all(|step| {
+            let t = step as f64 / steps as f64;
+            let cell = self.cell((from.0 + dx * t, from.1 + dy * t));
+            cell == start || !blocked[self.index(cell)]
+        })
+    }
+
+    fn cell(&self, point: Waypoint) -> Cell {
+        let column = (point.0 / self.cell_size).max(0.0) as usize;
+        let row = (point.1 / self.cell_size).max(0.0) as usize;
+        (column.min(self.columns - 1), row.min(self.rows - 1))
+    }
+
+    fn centre(&self, cell: Cell) -> Waypoint {
+        ((cell.0 as f64 + 0.5) * self.cell_size, (cell.1 as f64 + 0.5) * self.cell_size)
+    }
+
+    fn index(&self, cell: Cell) -> usize {
+        cell.1 * self.columns + cell.0
+    }
+}
@@ -0,0 +1,149 @@
+use nalgebra::Point2;
+use ncollide::bounding_volume;
+use ncollide::query::{self, Proximity, RayCast};
+use ncollide::shape::ConvexHull;
+use piston_window::*;
+use std::str::FromStr;
+
+use geometry::Pose;
+use grid::Bounds;
+use parser::{self, Expected};
+use random::Random;
+use unit::{DARK_GRAY, Id, SightRay, UnitShape};
+use value::Value;
+
+// A static convex polygon, its points are kept relative to its centre
+#[derive(Clone, Debug, PartialEq)]
+pub struct Obstacle {
+    pub id: Id,
+    pub pose: Pose,
+    shape: ConvexHull<Point2<f64>>,
+}
+
+// Written to replays as the id followed by the points in scene coordinates,
+// e.g. `<id> 0.0 0.0 10.0 0.0 10.0 10.0`
+impl ToString for Obstacle {
+    fn to_string(&self) -> String {
+        let mut s = self.id.hyphenated().to_string();
+        for point in self.points() {
+            s.push_str(&format!(" {:?} {:?}", point[0], point[1]));
+        }
+        s
+    }
+}
+
+impl FromStr for Obstacle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+
+        let id = match parts.next().map(Id::parse_str) {
+            Some(Ok(id)) => id,
+            _ => return Err(s.to_string()),
+        };
+
+        let coords = try!(parts.map(parser::finite)
+            .collect::<Option<Vec<f64>>>()
+            .ok_or_else(|| s.to_string()));
+        if coords.len() < 6 || coords.len() % 2 != 0 {
+            return Err(s.to_string());
+        }
+
+        Ok(Obstacle::new(id, coords.chunks(2).map(|c| [c[0], c[1]]).collect()))
+    }
+}
+
+impl Obstacle {
+    pub fn new(id: Id, points: Vec<[f64; 2]>) -> Obstacle {
+        let n = points.len() as f64;
+        let x = points.iter().fold(0.0, |sum, p| sum + p[0]) / n;
+        let y = points.iter().fold(0.0, |sum, p| sum + p[1]) / n;
+        let relative = points.iter().map(|p| Point2::new(p[0] - x, p[1] - y)).collect();
+
+        Obstacle {
+            id: id,
+            pose: Pose::new(x, y, 0.0),
+            shape: ConvexHull::new(relative),
+        }
+    }
+
+    // Either a box around its centre or a convex polygon:
+    //
+    //   {kind = "box", x = 400, y = 400, width = 200, height = 40, rotation = 0.5}
+    //   {kind = "polygon", points = {{100, 100}, {150, 100}, {120, 160}}}
+    pub fn from_value(value: &Value, random: &mut Random) -> Result<Obstacle, parser::Error> {
+        let points = match try!(value.string("kind")) {
+            "box" => {
+                let (x, y) = (try!(value.number("x")), try!(value.number("y")));
+                let half_width = try!(value.number("width")) / 2.0;
+                let half_height = try!(value.number("height")) / 2.0;
+                let rotation = match value.get("rotation") {
+                    Some(_) => try!(value.number("rotation")),
+                    None => 0.0,
+                };
+                let (sin, cos) = rotation.sin_cos();
+
+                [[-half_width, -half_height],
+                 [half_width, -half_height],
+                 [half_width, half_height],
+                 [-half_width, half_height]]
+                    .iter()
+                    .map(|p| [x + p[0] * cos - p[1] * sin, y + p[0] * sin + p[1] * cos])
+                    .collect()
+            }
+            "polygon" => {
+                let mut points = vec![];
+                for point in value.get("points").map_or(vec![], |points| points.array()) {
+                    let coords = point.array();
+                    match (coords.get(0), coords.get(1)) {
+                        (Some(&&Value::Number(x)), Some(&&Value::Number(y))) => {
+                            points.push([x, y])
+                        }
+                        _ => return Err(parser::Error::field("points", Expected::Float)),
+                    }
+                }
+                if points.len() < 3 {
+                    return Err(parser::Error::field("points", Expected::Obstacle));
+                }
+                points
+            }
+            _ => return Err(parser::Error::field("kind", Expected::Obstacle)),
+        };
+
+        Ok(Obstacle::new(random.gen_id(), points))
+    }
+
+    pub fn points(&self) -> Vec<[f64; 2]> {
+        self.shape
+            .points()
+            .iter()
+            .map(|p| [p.x + self.pose.x, p.y + self.pose.y])
+            .collect()
+    }
+
+    pub fn overlaps(&self, pose: &Pose, shape: &UnitShape) -> bool {
+        match query::proximity(&self.pose.isometry(),
+                               &self.shape,
+                               &pose.isometry(),
+                               shape,
+                               0.0) {
+            Proximity::Intersecting => true,
+            Proximity::Disjoint | Proximity::WithinMargin => false,
+        }
+    }
+
+    pub fn blocks_ray(&self, ray: &SightRay) -> bool {
+        self.shape.toi_with_ray(&self.pose.isometry(), ray, true).map_or(false, |toi| toi < 1.0)
+    }
+
+    pub fn bounds(&self) -> Bounds {
+        bounding_volume::aabb(&self.shape, &self.pose.isometry())
+    }
+
+    pub fn render<G: Graphics>(&self, c: &Context, g: &mut G) {
+        let (x, y, _) = self.pose.render_pose();
+        let points = self.shape.points().iter().map(|p| [p.x, -p.y]).collect::<Vec<_>>();
+        polygon(DARK_GRAY, &points, c.transform.trans(x, y), g);
+    }
+}
@@ -1,125 +1,367 @@
-use regex::Regex;
+use std::fmt;
 use std::str::FromStr;
 use uuid::Uuid;
 
 pub type Id = Uuid;
 
-#[derive(Debug)]
-pub enum TokenType {
+// Deepest nesting of calls and tuples, far beyond any state or delta, so that a
+// runaway string is an error rather than a stack overflow
+const MAX_DEPTH: usize = 32;
+
+// Grammar shared by states, deltas and timeline events:
+//
+//   expr  := word | word '(' exprs ')' | '(' exprs ')'
+//   exprs := expr (',' expr)*
+//
+// Words cover symbols, numbers and ids alike, they are only given a type once
+// the caller knows which one it expects at that position.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expected {
+    Arguments(usize),
+    Boolean,
+    BoundsPolicy,
+    Color,
+    Delta,
+    Depth(usize),
     Float,
-    Function,
     Id,
     Int,
-    Other,
-    Rest,
+    NonNegative,
+    Obstacle,
+    Point,
+    Positive,
+    PositiveInt,
+    Role,
+    RoleAttribute,
+    RoleName,
+    Shape,
+    State,
     Symbol,
-    Tuple,
+    Token(&'static str),
+    TimelineEvent,
 }
 
-pub type Error = (String, TokenType);
-
-pub fn read_fn(s: &str) -> Result<(&str, &str), Error> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(
-            r"^([a-zA-Z0-9_]+)\("
-        ).unwrap();
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expected::Arguments(n) => write!(f, "{} arguments", n),
+            Expected::Boolean => write!(f, "boolean"),
+            Expected::BoundsPolicy => write!(f, "clamp, bounce or despawn"),
+            Expected::Color => write!(f, "colour of 3 or 4 numbers"),
+            Expected::Delta => write!(f, "delta"),
+            Expected::Depth(depth) => write!(f, "at most {} levels of nesting", depth),
+            Expected::Float => write!(f, "float"),
+            Expected::Id => write!(f, "id"),
+            Expected::Int => write!(f, "integer"),
+            Expected::NonNegative => write!(f, "number of at least 0"),
+            Expected::Obstacle => write!(f, "box or convex polygon"),
+            Expected::Point => write!(f, "point `(x, y)`"),
+            Expected::Positive => write!(f, "positive number"),
+            Expected::PositiveInt => write!(f, "positive integer"),
+            Expected::Role => write!(f, "role"),
+            Expected::RoleAttribute => write!(f, "role attribute"),
+            Expected::RoleName => write!(f, "name made of letters, digits and underscores"),
+            Expected::Shape => write!(f, "convex polygon"),
+            Expected::State => write!(f, "state"),
+            Expected::Symbol => write!(f, "symbol"),
+            Expected::Token(token) => write!(f, "{}", token),
+            Expected::TimelineEvent => write!(f, "timeline event"),
+        }
     }
+}
 
-    parse_str(&RE, s, TokenType::Function)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+    pub column: usize,
+    pub expected: Expected,
+    pub found: String,
 }
 
-pub fn read_tuple(s: &str) -> Result<&str, Error> {
-    if s.starts_with('(') {
-        Ok(&s[1..])
-    } else {
-        Err((String::from(s), TokenType::Tuple))
+impl Error {
+    pub fn new(column: usize, expected: Expected, found: &str) -> Error {
+        Error {
+            column: column,
+            expected: expected,
+            found: found.to_string(),
+        }
+    }
+
+    // For values read from Lua tables, which have no column to point at
+    pub fn field(name: &str, expected: Expected) -> Error {
+        Error::new(0, expected, &format!("field `{}`", name))
     }
 }
 
-pub fn read_id(s: &str) -> Result<(Id, &str), Error> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(concat!(
-            r"^([0-9a-f]{8}-[0-9a-f]{4}-4[0-9a-f]{3}-[89ab][0-9a-f]{3}-[0-9a-f]{12})",
-            r"(, |\))?"
-        )).unwrap();
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.column > 0 {
+            try!(write!(f, "column {}: ", self.column));
+        }
+        write!(f, "expected {}, found {}", self.expected, self.found)
     }
+}
 
-    match parse_str(&RE, s, TokenType::Id) {
-        Ok((id_str, rest)) => {
-            if let Ok(id) = Id::parse_str(id_str) {
-                return Ok((id, rest));
-            }
-            Err((String::from(s), TokenType::Id))
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+    Open,
+    Close,
+    Comma,
+    Word(String),
+    End,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: TokenKind,
+    column: usize,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self.kind {
+            TokenKind::Open => "`(`".to_string(),
+            TokenKind::Close => "`)`".to_string(),
+            TokenKind::Comma => "`,`".to_string(),
+            TokenKind::Word(ref word) => format!("`{}`", word),
+            TokenKind::End => "end of input".to_string(),
         }
-        Err(e) => Err(e),
     }
 }
 
-pub fn read_float(s: &str) -> Result<(f64, &str), Error> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(concat!(
-            r"^(\d+.\d+)",
-            r"(, |\))?"
-        )).unwrap();
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Word(String, usize),
+    Call(String, Vec<Expr>, usize),
+    Tuple(Vec<Expr>, usize),
+}
+
+impl Expr {
+    pub fn column(&self) -> usize {
+        match *self {
+            Expr::Word(_, column) |
+            Expr::Call(_, _, column) |
+            Expr::Tuple(_, column) => column,
+        }
     }
 
-    match parse_str(&RE, s, TokenType::Float) {
-        Ok((f_str, rest)) => {
-            if let Ok(u) = f64::from_str(f_str) {
-                return Ok((u, rest));
-            }
-            Err((String::from(s), TokenType::Float))
+    pub fn describe(&self) -> String {
+        match *self {
+            Expr::Word(ref word, _) => format!("`{}`", word),
+            Expr::Call(ref name, _, _) => format!("`{}(...)`", name),
+            Expr::Tuple(_, _) => "tuple".to_string(),
         }
-        Err(e) => Err(e),
     }
-}
 
-pub fn read_int(s: &str) -> Result<(usize, &str), Error> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(concat!(
-            r"^(\d+)",
-            r"(, |\))?"
-        )).unwrap();
+    pub fn error(&self, expected: Expected) -> Error {
+        Error::new(self.column(), expected, &self.describe())
+    }
+
+    pub fn symbol(&self) -> Result<&str, Error> {
+        match *self {
+            Expr::Word(ref word, _) => Ok(word),
+            _ => Err(self.error(Expected::Symbol)),
+        }
+    }
+
+    pub fn float(&self) -> Result<f64, Error> {
+        let word = try!(self.symbol().map_err(|_| self.error(Expected::Float)));
+        finite(word).ok_or_else(|| self.error(Expected::Float))
+    }
+
+    pub fn int(&self) -> Result<usize, Error> {
+        let word = try!(self.symbol().map_err(|_| self.error(Expected::Int)));
+        usize::from_str(word).map_err(|_| self.error(Expected::Int))
     }
 
-    match parse_str(&RE, s, TokenType::Int) {
-        Ok((u_str, rest)) => {
-            if let Ok(u) = usize::from_str(u_str) {
-                return Ok((u, rest));
+    pub fn id(&self) -> Result<Id, Error> {
+        let word = try!(self.symbol().map_err(|_| self.error(Expected::Id)));
+        Id::parse_str(word).map_err(|_| self.error(Expected::Id))
+    }
+
+    pub fn point(&self) -> Result<(f64, f64), Error> {
+        match *self {
+            Expr::Tuple(ref args, column) => {
+                let args = try!(arguments(args, 2, column));
+                Ok((try!(args[0].float()), try!(args[1].float())))
             }
-            Err((String::from(s), TokenType::Int))
+            _ => Err(self.error(Expected::Point)),
         }
-        Err(e) => Err(e),
     }
 }
 
-pub fn read_rest(s: &str) -> Result<(&str, &str), Error> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(concat!(
-            r"^(.*)",
-            r"\)?"
-        )).unwrap();
+// Like `f64::from_str`, but refuses `nan` and `inf`, no position can be at either
+pub fn finite(word: &str) -> Option<f64> {
+    f64::from_str(word).ok().and_then(|n| if n.is_finite() { Some(n) } else { None })
+}
+
+// Checks that a call or tuple was given exactly `n` arguments
+pub fn arguments(args: &[Expr], n: usize, column: usize) -> Result<&[Expr], Error> {
+    if args.len() == n {
+        Ok(args)
+    } else {
+        Err(Error::new(column,
+                       Expected::Arguments(n),
+                       &format!("{} arguments", args.len())))
+    }
+}
+
+pub fn parse(s: &str) -> Result<Expr, Error> {
+    let tokens = try!(tokenize(s));
+    let mut parser = Parser {
+        tokens: tokens,
+        position: 0,
+        depth: 0,
+    };
+
+    let expr = try!(parser.expr());
+    try!(parser.expect(TokenKind::End, "end of input"));
+    Ok(expr)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '+' || c == '.'
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = vec![];
+    let mut chars = s.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let column = i + 1;
+        let kind = match c {
+            '(' => TokenKind::Open,
+            ')' => TokenKind::Close,
+            ',' => TokenKind::Comma,
+            c if c.is_whitespace() => continue,
+            c if is_word_char(c) => {
+                let mut end = i + c.len_utf8();
+                while let Some(&(j, next)) = chars.peek() {
+                    if !is_word_char(next) {
+                        break;
+                    }
+                    end = j + next.len_utf8();
+                    chars.next();
+                }
+                TokenKind::Word(s[i..end].to_string())
+            }
+            c => return Err(Error::new(column, Expected::Symbol, &format!("`{}`", c))),
+        };
+
+        tokens.push(Token {
+            kind: kind,
+            column: column,
+        });
     }
 
-    parse_str(&RE, s, TokenType::Rest)
+    tokens.push(Token {
+        kind: TokenKind::End,
+        column: s.len() + 1,
+    });
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
 }
 
-pub fn read_symbol(s: &str) -> Result<(&str, &str), Error> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(concat!(
-            r"^([a-zA-Z0-9_-]+)",
-            r"(, |\))?"
-        )).unwrap();
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position]
     }
 
-    parse_str(&RE, s, TokenType::Symbol)
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.position].clone();
+        if token.kind != TokenKind::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, kind: TokenKind, description: &'static str) -> Result<(), Error> {
+        let token = self.next();
+        if token.kind == kind {
+            Ok(())
+        } else {
+            Err(Error::new(token.column, Expected::Token(description), &token.describe()))
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr, Error> {
+        let token = self.next();
+
+        match token.kind {
+            TokenKind::Word(word) => {
+                if self.peek().kind == TokenKind::Open {
+                    self.next();
+                    let args = try!(self.exprs());
+                    Ok(Expr::Call(word, args, token.column))
+                } else {
+                    Ok(Expr::Word(word, token.column))
+                }
+            }
+            TokenKind::Open => {
+                let args = try!(self.exprs());
+                Ok(Expr::Tuple(args, token.column))
+            }
+            _ => Err(Error::new(token.column, Expected::Symbol, &token.describe())),
+        }
+    }
+
+    // Parses a comma separated list up to and including its closing paren,
+    // called right after the opening paren
+    fn exprs(&mut self) -> Result<Vec<Expr>, Error> {
+        if self.depth == MAX_DEPTH {
+            let open = &self.tokens[self.position - 1];
+            return Err(Error::new(open.column, Expected::Depth(MAX_DEPTH), &open.describe()));
+        }
+
+        self.depth += 1;
+        let exprs = self.list();
+        self.depth -= 1;
+        exprs
+    }
+
+    fn list(&mut self) -> Result<Vec<Expr>, Error> {
+        let mut exprs = vec![];
+
+        if self.peek().kind == TokenKind::Close {
+            self.next();
+            return Ok(exprs);
+        }
+
+        loop {
+            exprs.push(try!(self.expr()));
+
+            let token = self.next();
+            match token.kind {
+                TokenKind::Comma => {}
+                TokenKind::Close => return Ok(exprs),
+                _ => {
+                    return Err(Error::new(token.column,
+                                          Expected::Token("`,` or `)`"),
+                                          &token.describe()))
+                }
+            }
+        }
+    }
 }
 
-fn parse_str<'a>(re: &'a Regex, s: &'a str, t: TokenType) -> Result<(&'a str, &'a str), Error> {
-    if let Some(caps) = re.captures(s) {
-        let start = caps.pos(1).unwrap().0;
-        return Ok((&s[start..caps[1].len()], &s[caps[0].len()..]));
-    };
+#[cfg(test)]
+mod tests {
+    use super::{Expected, MAX_DEPTH, parse};
+
+    fn nested(depth: usize) -> String {
+        let open = (0..depth).map(|_| "(").collect::<String>();
+        let close = (0..depth).map(|_| ")").collect::<String>();
+        format!("{}x{}", open, close)
+    }
 
-    Err((String::from(s), t))
+    #[test]
+    fn refuses_deep_nesting() {
+        assert_eq!(parse(&nested(100000)).unwrap_err().expected, Expected::Depth(MAX_DEPTH));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+    }
 }
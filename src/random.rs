@@ -0,0 +1,33 @@
+use rand::{Rng, SeedableRng, StdRng};
+
+use unit::Id;
+
+pub type Seed = usize;
+
+// Every consumer of randomness draws from its own stream, so that the order in
+// which the interpreter threads and the main loop run cannot change the results
+#[derive(Clone, Copy, Debug)]
+pub enum Stream {
+    Timeline = 1,
+    Handlers = 2,
+    State = 3,
+    Obstacles = 4,
+}
+
+pub struct Random {
+    rng: StdRng,
+}
+
+impl Random {
+    pub fn new(seed: Seed, stream: Stream) -> Random {
+        Random { rng: StdRng::from_seed(&[seed, stream as usize]) }
+    }
+
+    pub fn gen_id(&mut self) -> Id {
+        self.rng.gen()
+    }
+
+    pub fn gen_f64(&mut self) -> f64 {
+        self.rng.gen()
+    }
+}
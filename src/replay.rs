@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::str::FromStr;
+
+use interpreter::{Delta, Error};
+use obstacle::Obstacle;
+use parser;
+use random::Seed;
+use role::{Role, Roles};
+use scene::Scene;
+
+// A replay is a text file with a `seed <seed>` and a `scene <scene>` header, an
+// `obstacle <obstacle>` line per obstacle and a `role <role>` line per role,
+// followed by one entry per line, each prefixed with the tick it was recorded on:
+//
+//   seed 42
+//   scene 800 800 bullet=despawn
+//   obstacle <id> 100.0 100.0 200.0 100.0 150.0 180.0
+//   role scout width=20.0 speed=140.0 ...
+//   12 dt 0.016666666666666666
+//   12 update_state(<id>, move(40.0, 12.0))
+//   12 spawn new_unit(bullet, <id>, 40.0, 12.0, 1.57, 1)
+//
+// A `dt` entry is only written when the tick length changes. Units spawned by
+// other units, like bullets, are `spawn` entries, they come to life while units
+// update rather than with the deltas at the end of the tick.
+
+enum Entry {
+    Dt(f64),
+    Delta(Delta),
+    Spawn(Delta),
+}
+
+pub struct Recorder {
+    writer: BufWriter<File>,
+    dt: Option<f64>,
+}
+
+impl Recorder {
+    pub fn create(path: &str, seed: Seed, scene: &Scene, roles: &Roles) -> Result<Recorder, Error> {
+        let mut writer = BufWriter::new(try!(File::create(path)));
+        try!(writeln!(writer, "seed {}", seed));
+        try!(writeln!(writer, "scene {}", scene.to_string()));
+        for obstacle in &scene.obstacles {
+            try!(writeln!(writer, "obstacle {}", obstacle.to_string()));
+        }
+        for role in roles.definitions() {
+            try!(writeln!(writer, "role {}", role.to_string()));
+        }
+
+        Ok(Recorder {
+            writer: writer,
+            dt: None,
+        })
+    }
+
+    pub fn record_dt(&mut self, tick: usize, dt: f64) -> Result<(), Error> {
+        if self.dt != Some(dt) {
+            try!(writeln!(self.writer, "{} dt {:?}", tick, dt));
+            self.dt = Some(dt);
+        }
+        Ok(())
+    }
+
+    pub fn record_delta(&mut self, tick: usize, delta: &Delta) -> Result<(), Error> {
+        try!(writeln!(self.writer, "{} {}", tick, delta.to_string()));
+        Ok(())
+    }
+
+    pub fn record_spawn(&mut self, tick: usize, delta: &Delta) -> Result<(), Error> {
+        try!(writeln!(self.writer, "{} spawn {}", tick, delta.to_string()));
+        Ok(())
+    }
+}
+
+pub struct Replay {
+    pub seed: Seed,
+    pub scene: Scene,
+    pub roles: Vec<Role>,
+    dt: f64,
+    entries: VecDeque<(usize, Entry)>,
+}
+
+impl Replay {
+    pub fn open(path: &str) -> Result<Replay, Error> {
+        let mut lines = BufReader::new(try!(File::open(path))).lines();
+
+        let header = match lines.next() {
+            Some(line) => try!(line),
+            None => return Err(Error::InvalidReplay(String::new())),
+        };
+        let seed = match Self::parse_header(&header, "seed ").map(Seed::from_str) {
+            Some(Ok(seed)) => seed,
+            _ => return Err(Error::InvalidReplay(header)),
+        };
+
+        let header = match lines.next() {
+            Some(line) => try!(line),
+            None => return Err(Error::InvalidReplay(String::new())),
+        };
+        let mut scene = match Self::parse_header(&header, "scene ").map(Scene::from_str) {
+            Some(Ok(scene)) => scene,
+            _ => return Err(Error::InvalidReplay(header)),
+        };
+
+        let mut roles = vec![];
+        let mut entries = VecDeque::new();
+        for line in lines {
+            let line = try!(line);
+
+            if let Some(obstacle) = Self::parse_header(&line, "obstacle ") {
+                match Obstacle::from_str(obstacle) {
+                    Ok(obstacle) => scene.obstacles.push(obstacle),
+                    Err(_) => return Err(Error::InvalidReplay(line)),
+                }
+                continue;
+            }
+
+            if let Some(role) = Self::parse_header(&line, "role ") {
+                match Role::from_str(role) {
+                    Ok(role) => roles.push(role),
+                    Err(_) => return Err(Error::InvalidReplay(line)),
+                }
+                continue;
+            }
+
+            match Self::parse_entry(&line) {
+                Some(entry) => entries.push_back(entry),
+                None => return Err(Error::InvalidReplay(line)),
+            }
+        }
+
+        Ok(Replay {
+            seed: seed,
+            scene: scene,
+            roles: roles,
+            dt: 0.0,
+            entries: entries,
+        })
+    }
+
+    // Returns the length of `tick`, the units spawned on it and every other
+    // delta recorded on it
+    pub fn next_tick(&mut self, tick: usize) -> (f64, Vec<Delta>, Vec<Delta>) {
+        let mut spawns = vec![];
+        let mut deltas = vec![];
+
+        while self.entries.front().map_or(false, |&(t, _)| t <= tick) {
+            match self.entries.pop_front().unwrap().1 {
+                Entry::Dt(dt) => self.dt = dt,
+                Entry::Delta(delta) => deltas.push(delta),
+                Entry::Spawn(delta) => spawns.push(delta),
+            }
+        }
+
+        (self.dt, spawns, deltas)
+    }
+
+    fn parse_header<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+        if line.starts_with(name) {
+            Some(&line[name.len()..])
+        } else {
+            None
+        }
+    }
+
+    fn parse_entry(line: &str) -> Option<(usize, Entry)> {
+        let mut parts = line.splitn(2, ' ');
+
+        let tick = match parts.next().map(usize::from_str) {
+            Some(Ok(tick)) => tick,
+            _ => return None,
+        };
+        let rest = match parts.next() {
+            Some(rest) => rest,
+            None => return None,
+        };
+
+        if rest.starts_with("dt ") {
+            parser::finite(&rest[3..]).map(|dt| (tick, Entry::Dt(dt)))
+        } else if rest.starts_with("spawn ") {
+            Delta::from_str(&rest[6..]).ok().map(|delta| (tick, Entry::Spawn(delta)))
+        } else {
+            Delta::from_str(rest).ok().map(|delta| (tick, Entry::Delta(delta)))
+        }
+    }
+}
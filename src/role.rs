@@ -0,0 +1,447 @@
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use geometry;
+use parser::{self, Expected};
+use unit::{BLACK, BLUE, Color, PURPLE, RED};
+use value::Value;
+use weapon::Weapon;
+
+pub const BULLET: &'static str = "bullet";
+
+const FOV_POINTS: [[f64; 2]; 3] = [[0.0, 0.0], [200.0, 150.0], [200.0, -150.0]];
+const RANGE_POINTS: [[f64; 2]; 3] = [[0.0, 0.0], [120.0, 20.0], [120.0, -20.0]];
+
+// Everything that sets one kind of unit apart from another. Shapes are given
+// relative to the unit, facing along the x axis.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Role {
+    pub name: String,
+    pub width: f64,
+    pub speed: f64,
+    pub turn_rate: f64,
+    pub color: Color,
+    pub team_colors: BTreeMap<usize, Color>,
+    pub fov: Vec<[f64; 2]>,
+    pub range: Vec<[f64; 2]>,
+    pub health: f64,
+    pub damage: f64,
+    pub can_shoot: bool,
+    pub fire_interval: f64,
+    pub magazine: usize,
+    pub reload_time: f64,
+    pub reserve: usize,
+    // Projectiles fly straight to the end of `max_range` and hurt what they hit
+    pub projectile: bool,
+    pub max_range: f64,
+    // Seconds between calls to `<role>_on_tick`, never called when 0
+    pub tick_interval: f64,
+}
+
+// Written to replays as the name followed by every attribute, e.g.
+// `scout width=20.0 speed=140.0 ... color=0.0,1.0,0.0,1.0 fov=0.0,0.0,300.0,200.0,300.0,-200.0`
+impl ToString for Role {
+    fn to_string(&self) -> String {
+        let mut s = format!("{} width={:?} speed={:?} turn_rate={:?} color={}",
+                            self.name,
+                            self.width,
+                            self.speed,
+                            self.turn_rate,
+                            join(&self.color.iter().map(|c| *c as f64).collect::<Vec<_>>()));
+        for (team, color) in &self.team_colors {
+            let color = color.iter().map(|c| *c as f64).collect::<Vec<_>>();
+            s.push_str(&format!(" team_color.{}={}", team, join(&color)));
+        }
+        s.push_str(&format!(" fov={} range={}",
+                            join(&flatten(&self.fov)),
+                            join(&flatten(&self.range))));
+        s.push_str(&format!(" health={:?} damage={:?} can_shoot={} fire_interval={:?} \
+                             magazine={} reload_time={:?} reserve={} projectile={} max_range={:?} \
+                             tick_interval={:?}",
+                            self.health,
+                            self.damage,
+                            self.can_shoot,
+                            self.fire_interval,
+                            self.magazine,
+                            self.reload_time,
+                            self.reserve,
+                            self.projectile,
+                            self.max_range,
+                            self.tick_interval));
+        s
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+
+        // Every attribute is written out, only team colours can be missing
+        let mut role = match parts.next() {
+            Some(name) if is_name(name) => Role::soldier().renamed(name),
+            _ => return Err(s.to_string()),
+        };
+        role.team_colors.clear();
+
+        for part in parts {
+            let mut pair = part.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(key), Some(value)) if role.set(key, value).is_some() => {}
+                _ => return Err(s.to_string()),
+            }
+        }
+
+        Ok(role)
+    }
+}
+
+impl Role {
+    pub fn soldier() -> Role {
+        let mut team_colors = BTreeMap::new();
+        team_colors.insert(1, BLUE);
+
+        Role {
+            name: "soldier".to_string(),
+            width: 25.0,
+            speed: 100.0,
+            turn_rate: 1.2,
+            color: PURPLE,
+            team_colors: team_colors,
+            fov: FOV_POINTS.to_vec(),
+            range: RANGE_POINTS.to_vec(),
+            health: 100.0,
+            damage: 0.0,
+            can_shoot: true,
+            fire_interval: 0.5,
+            magazine: 5,
+            reload_time: 2.0,
+            reserve: 15,
+            projectile: false,
+            max_range: 400.0,
+            tick_interval: 1.0,
+        }
+    }
+
+    pub fn general() -> Role {
+        Role {
+            width: 50.0,
+            color: RED,
+            team_colors: BTreeMap::new(),
+            health: 200.0,
+            ..Role::soldier().renamed("general")
+        }
+    }
+
+    pub fn bullet() -> Role {
+        Role {
+            width: 5.0,
+            speed: 150.0,
+            color: BLACK,
+            team_colors: BTreeMap::new(),
+            health: 1.0,
+            damage: 50.0,
+            can_shoot: false,
+            projectile: true,
+            tick_interval: 0.0,
+            ..Role::soldier().renamed(BULLET)
+        }
+    }
+
+    // Scenarios define roles in a global table, keyed by name. Every attribute
+    // is optional and taken from `base` when left out, team colours are listed
+    // by team and shapes are either points or a cone:
+    //
+    //   roles = {sniper = {speed = 60, color = {0.2, 0.2, 0.2},
+    //                      fov = {angle = 0.6, distance = 400},
+    //                      range = {{0, 0}, {300, 10}, {300, -10}}}}
+    pub fn from_value(name: &str, value: &Value, base: &Role) -> Result<Role, parser::Error> {
+        match *value {
+            Value::Table(_) => {}
+            _ => return Err(parser::Error::field(name, Expected::Role)),
+        }
+
+        // Names end up in replays and Lua globals, they must be identifiers
+        if !is_name(name) {
+            return Err(parser::Error::field(name, Expected::RoleName));
+        }
+
+        let mut role = base.renamed(name);
+
+        for (key, field) in value.fields() {
+            match key {
+                // Units are boxes of that width, which cannot be empty
+                "width" => role.width = try!(positive(value, key)),
+                "speed" => role.speed = try!(non_negative(value, key)),
+                // Looks and rotations would never get round to their heading
+                "turn_rate" => role.turn_rate = try!(positive(value, key)),
+                "color" => role.color = try!(color(field, key)),
+                "team_colors" => {
+                    role.team_colors.clear();
+                    for (i, team_color) in field.array().into_iter().enumerate() {
+                        role.team_colors.insert(i + 1, try!(color(team_color, key)));
+                    }
+                }
+                "fov" => role.fov = try!(shape(field, key)),
+                "range" => role.range = try!(shape(field, key)),
+                "health" => role.health = try!(non_negative(value, key)),
+                "damage" => role.damage = try!(value.number(key)),
+                "can_shoot" => role.can_shoot = try!(boolean(field, key)),
+                "fire_interval" => role.fire_interval = try!(value.number(key)),
+                "magazine" => {
+                    // An empty magazine could never be reloaded nor run out
+                    role.magazine = try!(value.int(key));
+                    if role.magazine == 0 {
+                        return Err(parser::Error::field(key, Expected::PositiveInt));
+                    }
+                }
+                "reload_time" => role.reload_time = try!(value.number(key)),
+                "reserve" => role.reserve = try!(value.int(key)),
+                "projectile" => role.projectile = try!(boolean(field, key)),
+                "max_range" => role.max_range = try!(non_negative(value, key)),
+                "tick_interval" => role.tick_interval = try!(value.number(key)),
+                _ => return Err(parser::Error::field(key, Expected::RoleAttribute)),
+            }
+        }
+
+        Ok(role)
+    }
+
+    pub fn color(&self, team: usize) -> Color {
+        *self.team_colors.get(&team).unwrap_or(&self.color)
+    }
+
+    pub fn weapon(&self) -> Option<Weapon> {
+        if self.can_shoot {
+            Some(Weapon::new(self.fire_interval, self.magazine, self.reload_time, self.reserve))
+        } else {
+            None
+        }
+    }
+
+    fn renamed(&self, name: &str) -> Role {
+        Role { name: name.to_string(), ..self.clone() }
+    }
+
+    // Sets one attribute from its replay form, None when the key or value is invalid
+    fn set(&mut self, key: &str, value: &str) -> Option<()> {
+        let numbers = match value.split(',').map(parser::finite).collect::<Option<Vec<_>>>() {
+            Some(numbers) => numbers,
+            None => vec![],
+        };
+        let number = if numbers.len() == 1 { Some(numbers[0]) } else { None };
+
+        if key.starts_with("team_color.") {
+            let team = usize::from_str(&key["team_color.".len()..]).ok();
+            return match (team, to_color(&numbers)) {
+                (Some(team), Some(color)) => {
+                    self.team_colors.insert(team, color);
+                    Some(())
+                }
+                _ => None,
+            };
+        }
+
+        match key {
+            "width" => number.and_then(above_zero).map(|n| self.width = n),
+            "speed" => number.and_then(not_below_zero).map(|n| self.speed = n),
+            "turn_rate" => number.and_then(above_zero).map(|n| self.turn_rate = n),
+            "color" => to_color(&numbers).map(|c| self.color = c),
+            "fov" => to_points(&numbers).map(|p| self.fov = p),
+            "range" => to_points(&numbers).map(|p| self.range = p),
+            "health" => number.and_then(not_below_zero).map(|n| self.health = n),
+            "damage" => number.map(|n| self.damage = n),
+            "can_shoot" => bool::from_str(value).ok().map(|b| self.can_shoot = b),
+            "fire_interval" => number.map(|n| self.fire_interval = n),
+            "magazine" => {
+                match usize::from_str(value) {
+                    Ok(n) if n > 0 => {
+                        self.magazine = n;
+                        Some(())
+                    }
+                    _ => None,
+                }
+            }
+            "reload_time" => number.map(|n| self.reload_time = n),
+            "reserve" => usize::from_str(value).ok().map(|n| self.reserve = n),
+            "projectile" => bool::from_str(value).ok().map(|b| self.projectile = b),
+            "max_range" => number.and_then(not_below_zero).map(|n| self.max_range = n),
+            "tick_interval" => number.map(|n| self.tick_interval = n),
+            _ => None,
+        }
+    }
+}
+
+// The built in roles, overridden by any scenario role with the same name
+#[derive(Clone, Debug, PartialEq)]
+pub struct Roles {
+    roles: BTreeMap<String, Rc<Role>>,
+}
+
+impl Roles {
+    pub fn new(definitions: Vec<Role>) -> Roles {
+        let mut roles = BTreeMap::new();
+
+        for role in vec![Role::soldier(), Role::general(), Role::bullet()]
+            .into_iter()
+            .chain(definitions.into_iter()) {
+            roles.insert(role.name.clone(), Rc::new(role));
+        }
+
+        Roles { roles: roles }
+    }
+
+    // Roles that are new are based on `soldier`
+    pub fn from_value(value: &Value) -> Result<Vec<Role>, parser::Error> {
+        let builtin = Roles::new(vec![]);
+        let mut roles = vec![];
+
+        for (name, definition) in value.fields() {
+            let base = builtin.get(name).unwrap_or_else(|| builtin.get("soldier").unwrap());
+            roles.push(try!(Role::from_value(name, definition, &base)));
+        }
+
+        Ok(roles)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Rc<Role>> {
+        self.roles.get(name).cloned()
+    }
+
+    pub fn definitions(&self) -> Vec<&Role> {
+        self.roles.values().map(|role| &**role).collect()
+    }
+}
+
+fn boolean(value: &Value, name: &str) -> Result<bool, parser::Error> {
+    match *value {
+        Value::Boolean(b) => Ok(b),
+        _ => Err(parser::Error::field(name, Expected::Boolean)),
+    }
+}
+
+fn color(value: &Value, name: &str) -> Result<Color, parser::Error> {
+    let mut numbers = vec![];
+    for component in value.array() {
+        match *component {
+            Value::Number(n) => numbers.push(n),
+            _ => return Err(parser::Error::field(name, Expected::Color)),
+        }
+    }
+    to_color(&numbers).ok_or_else(|| parser::Error::field(name, Expected::Color))
+}
+
+fn shape(value: &Value, name: &str) -> Result<Vec<[f64; 2]>, parser::Error> {
+    if value.get("angle").is_some() {
+        return Ok(geometry::cone(try!(value.number("angle")), try!(positive(value, "distance"))));
+    }
+
+    let mut numbers = vec![];
+    for point in value.array() {
+        let coords = point.array();
+        match (coords.get(0), coords.get(1)) {
+            (Some(&&Value::Number(x)), Some(&&Value::Number(y))) => numbers.extend(&[x, y]),
+            _ => return Err(parser::Error::field(name, Expected::Float)),
+        }
+    }
+    to_points(&numbers).ok_or_else(|| parser::Error::field(name, Expected::Shape))
+}
+
+fn is_name(name: &str) -> bool {
+    let identifier = |c: char, first: bool| {
+        c == '_' || (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') ||
+        (!first && c >= '0' && c <= '9')
+    };
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if identifier(c, true) => chars.all(|c| identifier(c, false)),
+        _ => false,
+    }
+}
+
+fn positive(value: &Value, name: &str) -> Result<f64, parser::Error> {
+    let number = try!(value.number(name));
+    above_zero(number).ok_or_else(|| parser::Error::field(name, Expected::Positive))
+}
+
+fn non_negative(value: &Value, name: &str) -> Result<f64, parser::Error> {
+    let number = try!(value.number(name));
+    not_below_zero(number).ok_or_else(|| parser::Error::field(name, Expected::NonNegative))
+}
+
+fn above_zero(number: f64) -> Option<f64> {
+    if number > 0.0 { Some(number) } else { None }
+}
+
+fn not_below_zero(number: f64) -> Option<f64> {
+    if number >= 0.0 { Some(number) } else { None }
+}
+
+// Colours have three or four components, alpha defaults to opaque
+fn to_color(numbers: &[f64]) -> Option<Color> {
+    match numbers.len() {
+        3 => Some([numbers[0] as f32, numbers[1] as f32, numbers[2] as f32, 1.0]),
+        4 => Some([numbers[0] as f32, numbers[1] as f32, numbers[2] as f32, numbers[3] as f32]),
+        _ => None,
+    }
+}
+
+// Shapes need at least a triangle to be convex
+fn to_points(numbers: &[f64]) -> Option<Vec<[f64; 2]>> {
+    if numbers.len() < 6 || numbers.len() % 2 != 0 {
+        return None;
+    }
+    Some(numbers.chunks(2).map(|c| [c[0], c[1]]).collect())
+}
+
+fn flatten(points: &[[f64; 2]]) -> Vec<f64> {
+    points.iter().flat_map(|p| p.iter().cloned()).collect()
+}
+
+fn join(numbers: &[f64]) -> String {
+    numbers.iter().map(|n| format!("{:?}", n)).collect::<Vec<_>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+
+    use parser::Expected;
+    use value::{Key, Value};
+    use super::Role;
+
+    fn width(width: f64) -> Value {
+        let mut table = BTreeMap::new();
+        table.insert(Key::Name("width".to_string()), Value::Number(width));
+        Value::Table(table)
+    }
+
+    #[test]
+    fn refuses_non_positive_widths() {
+        for &n in &[0.0, -5.0] {
+            let err = Role::from_value("scout", &width(n), &Role::soldier()).unwrap_err();
+            assert_eq!(err.expected, Expected::Positive);
+
+            let mut line = Role::soldier().to_string();
+            line = line.replace("width=25.0", &format!("width={:?}", n));
+            assert!(Role::from_str(&line).is_err());
+        }
+
+        assert_eq!(Role::from_value("scout", &width(5.0), &Role::soldier()).unwrap().width, 5.0);
+    }
+
+    #[test]
+    fn refuses_names_that_are_not_identifiers() {
+        for name in &["", "2nd", "heavy tank", "sniper-1", "é"] {
+            let err = Role::from_value(name, &width(5.0), &Role::soldier()).unwrap_err();
+            assert_eq!(err.expected, Expected::RoleName);
+        }
+
+        for name in &["_scout", "Tank2", "heavy_tank"] {
+            assert!(Role::from_value(name, &width(5.0), &Role::soldier()).is_ok());
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use ncollide::bounding_volume::{self, BoundingVolume};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use geometry::Pose;
+use obstacle::Obstacle;
+use parser::{self, Expected};
+use random::Random;
+use role::Role;
+use unit::{Id, Ids, SightRay, Unit, UnitShape, UnitState};
+use value::Value;
+
+pub const DEFAULT_SIZE: [u32; 2] = [800, 800];
+
+// What happens to a unit that leaves the scene
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BoundsPolicy {
+    Clamp,
+    Bounce,
+    Despawn,
+}
+
+impl ToString for BoundsPolicy {
+    fn to_string(&self) -> String {
+        match *self {
+            BoundsPolicy::Clamp => "clamp".to_string(),
+            BoundsPolicy::Bounce => "bounce".to_string(),
+            BoundsPolicy::Despawn => "despawn".to_string(),
+        }
+    }
+}
+
+impl FromStr for BoundsPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(BoundsPolicy::Clamp),
+            "bounce" => Ok(BoundsPolicy::Bounce),
+            "despawn" => Ok(BoundsPolicy::Despawn),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scene {
+    pub size: [u32; 2],
+    policies: BTreeMap<String, BoundsPolicy>,
+    pub obstacles: Vec<Obstacle>,
+    pub units_block_view: bool,
+}
+
+// Written to replays as `<width> <height>` followed by the policies set by the
+// scenario and the flags that are on, e.g. `1200 800 bullet=bounce units_block_view`
+impl ToString for Scene {
+    fn to_string(&self) -> String {
+        let mut s = format!("{} {}", self.size[0], self.size[1]);
+        for (role, policy) in &self.policies {
+            s.push_str(&format!(" {}={}", role, policy.to_string()));
+        }
+        if self.units_block_view {
+            s.push_str(" units_block_view");
+        }
+        s
+    }
+}
+
+impl FromStr for Scene {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+
+        let width = parts.next().map(u32::from_str);
+        let height = parts.next().map(u32::from_str);
+
+        let mut scene = match (width, height) {
+            (Some(Ok(width)), Some(Ok(height))) if width > 0 && height > 0 => {
+                Scene::new([width, height])
+            }
+            _ => return Err(s.to_string()),
+        };
+
+        for part in parts {
+            if part == "units_block_view" {
+                scene.units_block_view = true;
+                continue;
+            }
+
+            let mut pair = part.splitn(2, '=');
+            match (pair.next(), pair.next().map(BoundsPolicy::from_str)) {
+                (Some(role), Some(Ok(policy))) => {
+                    scene.policies.insert(role.to_string(), policy);
+                }
+                _ => return Err(s.to_string()),
+            }
+        }
+
+        Ok(scene)
+    }
+}
+
+impl Scene {
+    pub fn new(size: [u32; 2]) -> Scene {
+        Scene {
+            size: size,
+            policies: BTreeMap::new(),
+            obstacles: vec![],
+            units_block_view: false,
+        }
+    }
+
+    // Scenarios describe their scene with a global table, every field is optional:
+    //
+    //   scene = {width = 1200, height = 800, bounds = {soldier = "bounce"},
+    //            obstacles = {...}, units_block_view = true}
+    pub fn from_value(value: &Value, random: &mut Random) -> Result<Scene, parser::Error> {
+        let mut scene = Scene::new(DEFAULT_SIZE);
+
+        if value.get("width").is_some() {
+            scene.size[0] = try!(value.int("width")) as u32;
+        }
+        if value.get("height").is_some() {
+            scene.size[1] = try!(value.int("height")) as u32;
+        }
+        // Navigation lays its grid over the scene, which needs at least one cell
+        if scene.size[0] == 0 {
+            return Err(parser::Error::field("width", Expected::PositiveInt));
+        }
+        if scene.size[1] == 0 {
+            return Err(parser::Error::field("height", Expected::PositiveInt));
+        }
+
+        if let Some(bounds) = value.get("bounds") {
+            for (role, _) in bounds.fields() {
+                let policy = try!(BoundsPolicy::from_str(try!(bounds.string(role)))
+                    .map_err(|_| parser::Error::field(role, Expected::BoundsPolicy)));
+                scene.policies.insert(role.to_string(), policy);
+            }
+        }
+
+        match value.get("units_block_view") {
+            Some(&Value::Boolean(block)) => scene.units_block_view = block,
+            Some(_) => return Err(parser::Error::field("units_block_view", Expected::Boolean)),
+            None => {}
+        }
+
+        if let Some(obstacles) = value.get("obstacles") {
+            for obstacle in obstacles.array() {
+                scene.obstacles.push(try!(Obstacle::from_value(obstacle, random)));
+            }
+        }
+
+        Ok(scene)
+    }
+
+    pub fn obstacle(&self, id: &Id) -> Option<&Obstacle> {
+        self.obstacles.iter().find(|obstacle| obstacle.id == *id)
+    }
+
+    pub fn obstacles_hit(&self, pose: &Pose, shape: &UnitShape) -> Ids {
+        let bounds = bounding_volume::aabb(shape, &pose.isometry());
+
+        self.obstacles
+            .iter()
+            .filter(|obstacle| obstacle.bounds().intersects(&bounds))
+            .filter(|obstacle| obstacle.overlaps(pose, shape))
+            .map(|obstacle| obstacle.id)
+            .collect()
+    }
+
+    pub fn blocks_view(&self, ray: &SightRay) -> bool {
+        self.obstacles.iter().any(|obstacle| obstacle.blocks_ray(ray))
+    }
+
+    // Undoes as little of a unit's last move as needed to get it out of every
+    // obstacle, so that it slides along walls, and returns the obstacles it hit
+    pub fn block(&self, unit: &mut Unit, previous: &Pose) -> Ids {
+        let hit = self.obstacles_hit(&unit.pose, &unit.shape);
+        if hit.is_empty() {
+            return hit;
+        }
+
+        let rotation = unit.pose.rotation;
+        let candidates = [Pose::new(unit.pose.x, previous.y, rotation),
+                          Pose::new(previous.x, unit.pose.y, rotation),
+                          Pose::new(previous.x, previous.y, rotation),
+                          *previous];
+
+        if let Some(pose) = candidates.iter()
+            .find(|pose| self.obstacles_hit(pose, &unit.shape).is_empty()) {
+            unit.pose = *pose;
+        }
+
+        hit
+    }
+
+    pub fn policy(&self, role: &Role) -> BoundsPolicy {
+        match self.policies.get(&role.name) {
+            Some(policy) => *policy,
+            None if role.projectile => BoundsPolicy::Despawn,
+            None => BoundsPolicy::Clamp,
+        }
+    }
+
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        x >= 0.0 && x <= self.width() && y >= 0.0 && y <= self.height()
+    }
+
+    pub fn width(&self) -> f64 {
+        self.size[0] as f64
+    }
+
+    pub fn height(&self) -> f64 {
+        self.size[1] as f64
+    }
+
+    // Brings a unit that has left the scene back inside, or kills it. Targets of
+    // moves are adjusted too, so that a unit does not keep pushing into the edge.
+    pub fn enforce(&self, unit: &mut Unit) {
+        if self.contains(unit.pose.x, unit.pose.y) {
+            return;
+        }
+
+        match self.policy(&unit.role) {
+            BoundsPolicy::Clamp => {
+                unit.pose = unit.pose.clamp(self.width(), self.height());
+                if let UnitState::Move(x, y) = unit.state {
+                    unit.state = UnitState::Move(x.max(0.0).min(self.width()),
+                                                 y.max(0.0).min(self.height()));
+                }
+            }
+            BoundsPolicy::Bounce => self.bounce(unit),
+            BoundsPolicy::Despawn => unit.state = UnitState::Dead,
+        }
+    }
+
+    fn bounce(&self, unit: &mut Unit) {
+        let (width, height) = (self.width(), self.height());
+        let (mut x, mut y) = match unit.state {
+            UnitState::Move(x, y) => (x, y),
+            _ => (unit.pose.x, unit.pose.y),
+        };
+
+        if unit.pose.x < 0.0 {
+            unit.pose = unit.pose.reflect_x(0.0);
+            x = -x;
+        } else if unit.pose.x > width {
+            unit.pose = unit.pose.reflect_x(width);
+            x = 2.0 * width - x;
+        }
+
+        if unit.pose.y < 0.0 {
+            unit.pose = unit.pose.reflect_y(0.0);
+            y = -y;
+        } else if unit.pose.y > height {
+            unit.pose = unit.pose.reflect_y(height);
+            y = 2.0 * height - y;
+        }
+
+        if let UnitState::Move(_, _) = unit.state {
+            unit.state = UnitState::Move(x, y);
+        }
+    }
+}
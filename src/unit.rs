@@ -1,84 +1,81 @@
 use nalgebra::{Point2, Vector2};
-use ncollide::query::{self, PointQuery, Proximity};
+use ncollide::bounding_volume;
+use ncollide::query::{self, Proximity, Ray, RayCast};
 use ncollide::shape::{ConvexHull, Cuboid};
 use piston_window::*;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::rc::Rc;
 use std::f64;
 use std::str::FromStr;
 use uuid::Uuid;
 
-use geometry::Pose;
-use parser::{self, TokenType};
+use geometry::{self, Pose};
+use grid::Bounds;
+use navigation::{Navigation, Waypoint};
+use parser::{self, Expected, Expr};
+use random::Random;
+use role::{self, Role, Roles};
+use scene::Scene;
+use value::Value;
+use weapon::{Weapon, WeaponEvent};
 
 pub type Color = [f32; 4];
 pub type Id = Uuid;
-pub type Ids = HashSet<Id>;
+pub type Ids = BTreeSet<Id>;
 
 pub type UnitShape = Cuboid<Vector2<f64>>;
-pub type Views = HashMap<Id, (Pose, UnitShape)>;
+pub type SightRay = Ray<Point2<f64>>;
+// Read-only copy of every unit's pose and shape, taken once per tick and shared
+// by all units while they update
+pub type Snapshot = HashMap<Id, (Pose, UnitShape)>;
 
 pub const BLUE: Color = [0.0, 0.0, 1.0, 1.0];
 pub const PURPLE: Color = [0.5, 0.5, 1.0, 1.0];
 pub const GREEN: Color = [0.0, 1.0, 0.0, 1.0];
 pub const RED: Color = [1.0, 0.0, 0.0, 1.0];
 pub const BLACK: Color = [0.0, 0.0, 0.0, 1.0];
+pub const DARK_GRAY: Color = [0.3, 0.3, 0.3, 1.0];
 pub const GRAY: Color = [0.0, 0.0, 0.0, 0.3];
 pub const LIGHT_GRAY: Color = [0.0, 0.0, 0.0, 0.1];
 
-const FOV_POINTS: [[f64; 2]; 3] = [[0.0, 0.0], [200.0, 150.0], [200.0, -150.0]];
-const RANGE_POINTS: [[f64; 2]; 3] = [[0.0, 0.0], [120.0, 20.0], [120.0, -20.0]];
-
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum UnitRole {
-    Soldier,
-    General,
-    Bullet,
-}
-
-impl ToString for UnitRole {
-    fn to_string(&self) -> String {
-        match *self {
-            UnitRole::Soldier => "soldier".to_string(),
-            UnitRole::General => "general".to_string(),
-            UnitRole::Bullet => "bullet".to_string(),
-        }
-    }
-}
-
-impl FromStr for UnitRole {
-    type Err = String;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "soldier" => Ok(UnitRole::Soldier),
-            "general" => Ok(UnitRole::General),
-            "bullet" => Ok(UnitRole::Bullet),
-            _ => Err(s.to_string()),
-        }
-    }
-}
-
 #[derive(Clone, Debug, PartialEq)]
 pub enum UnitState {
     Command(Id, Box<UnitState>),
     Dead,
+    Follow(Id, f64),
+    Guard(f64, f64, f64),
     Idle,
     Look(f64, f64),
     Move(f64, f64),
+    Patrol(Vec<Waypoint>),
+    Rotate(f64),
     Shoot(Id),
+    Wait(f64),
 }
 
 const IDLE: &'static UnitState = &UnitState::Idle;
+// How close to the point a look has to turn, in radians
+const LOOK_EPSILON: f64 = 1e-6;
 
 impl ToString for UnitState {
     fn to_string(&self) -> String {
         match *self {
             UnitState::Command(id, ref state) => format!("command({}, {})", id, state.to_string()),
             UnitState::Dead => "dead".to_string(),
+            UnitState::Follow(id, distance) => format!("follow({}, {:?})", id, distance),
+            UnitState::Guard(x, y, radius) => format!("guard({:?}, {:?}, {:?})", x, y, radius),
             UnitState::Idle => "idle".to_string(),
-            UnitState::Look(x, y) => format!("look({:.*}, {:.*})", 2, x, 2, y),
-            UnitState::Move(x, y) => format!("move({:.*}, {:.*})", 2, x, 2, y),
+            UnitState::Look(x, y) => format!("look({:?}, {:?})", x, y),
+            UnitState::Move(x, y) => format!("move({:?}, {:?})", x, y),
+            UnitState::Patrol(ref points) => {
+                let points = points.iter()
+                    .map(|&(x, y)| format!("({:?}, {:?})", x, y))
+                    .collect::<Vec<_>>();
+                format!("patrol({})", points.join(", "))
+            }
+            UnitState::Rotate(angle) => format!("rotate({:?})", angle),
             UnitState::Shoot(id) => format!("shoot({})", id),
+            UnitState::Wait(seconds) => format!("wait({:?})", seconds),
         }
     }
 }
@@ -87,33 +84,114 @@ impl FromStr for UnitState {
     type Err = parser::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match parser::read_symbol(s) {
-            Ok(("dead", _)) => return Ok(UnitState::Dead),
-            Ok(("idle", _)) => return Ok(UnitState::Idle),
-            _ => {}
-        }
+        UnitState::from_expr(&try!(parser::parse(s)))
+    }
+}
 
-        match parser::read_fn(s) {
-            Ok(("command", s)) => {
-                let (id, s) = try!(parser::read_id(s));
-                let state = UnitState::from_str(s).unwrap();
-                Ok(UnitState::Command(id, Box::new(state)))
+impl UnitState {
+    pub fn from_expr(expr: &Expr) -> Result<UnitState, parser::Error> {
+        match *expr {
+            Expr::Word(ref word, _) => {
+                match word.as_str() {
+                    "dead" => Ok(UnitState::Dead),
+                    "idle" => Ok(UnitState::Idle),
+                    _ => Err(expr.error(Expected::State)),
+                }
             }
-            Ok(("look", s)) => {
-                let (x, s) = try!(parser::read_float(s));
-                let (y, _) = try!(parser::read_float(s));
-                Ok(UnitState::Look(x, y))
+            Expr::Call(ref name, ref args, column) => {
+                match name.as_str() {
+                    "command" => {
+                        let args = try!(parser::arguments(args, 2, column));
+                        let state = try!(UnitState::from_expr(&args[1]));
+                        Ok(UnitState::Command(try!(args[0].id()), Box::new(state)))
+                    }
+                    "follow" => {
+                        let args = try!(parser::arguments(args, 2, column));
+                        Ok(UnitState::Follow(try!(args[0].id()), try!(args[1].float())))
+                    }
+                    "guard" => {
+                        let args = try!(parser::arguments(args, 3, column));
+                        Ok(UnitState::Guard(try!(args[0].float()),
+                                            try!(args[1].float()),
+                                            try!(args[2].float())))
+                    }
+                    "look" => {
+                        let args = try!(parser::arguments(args, 2, column));
+                        Ok(UnitState::Look(try!(args[0].float()), try!(args[1].float())))
+                    }
+                    "move" => {
+                        let args = try!(parser::arguments(args, 2, column));
+                        Ok(UnitState::Move(try!(args[0].float()), try!(args[1].float())))
+                    }
+                    "patrol" if args.is_empty() => Err(expr.error(Expected::Point)),
+                    "patrol" => {
+                        let points = try!(args.iter().map(Expr::point).collect());
+                        Ok(UnitState::Patrol(points))
+                    }
+                    "rotate" => {
+                        let args = try!(parser::arguments(args, 1, column));
+                        Ok(UnitState::Rotate(try!(args[0].float())))
+                    }
+                    "shoot" => {
+                        let args = try!(parser::arguments(args, 1, column));
+                        Ok(UnitState::Shoot(try!(args[0].id())))
+                    }
+                    "wait" => {
+                        let args = try!(parser::arguments(args, 1, column));
+                        Ok(UnitState::Wait(try!(args[0].float())))
+                    }
+                    _ => Err(expr.error(Expected::State)),
+                }
             }
-            Ok(("move", s)) => {
-                let (x, s) = try!(parser::read_float(s));
-                let (y, _) = try!(parser::read_float(s));
-                Ok(UnitState::Move(x, y))
+            Expr::Tuple(_, _) => Err(expr.error(Expected::State)),
+        }
+    }
+
+    // Handlers can return either a table, e.g. `{kind="move", x=12, y=40}` or
+    // `{kind="patrol", points={{0, 0}, {100, 0}}}`, or the string form built by
+    // the helpers in core.lua
+    pub fn from_value(value: &Value) -> Result<UnitState, parser::Error> {
+        let kind = match *value {
+            Value::String(ref s) => return UnitState::from_str(s),
+            _ => try!(value.string("kind")),
+        };
+
+        match kind {
+            "command" => {
+                let state = match value.get("state") {
+                    Some(state) => try!(UnitState::from_value(state)),
+                    None => return Err(parser::Error::field("state", Expected::State)),
+                };
+                Ok(UnitState::Command(try!(value.id("id")), Box::new(state)))
             }
-            Ok(("shoot", s)) => {
-                let (id, _) = try!(parser::read_id(s));
-                Ok(UnitState::Shoot(id))
+            "dead" => Ok(UnitState::Dead),
+            "follow" => Ok(UnitState::Follow(try!(value.id("id")), try!(value.number("distance")))),
+            "guard" => {
+                Ok(UnitState::Guard(try!(value.number("x")),
+                                    try!(value.number("y")),
+                                    try!(value.number("radius"))))
             }
-            _ => Err((String::from(s), TokenType::Other)),
+            "idle" => Ok(UnitState::Idle),
+            "look" => Ok(UnitState::Look(try!(value.number("x")), try!(value.number("y")))),
+            "move" => Ok(UnitState::Move(try!(value.number("x")), try!(value.number("y")))),
+            "patrol" => {
+                let mut points = vec![];
+                for point in value.get("points").map_or(vec![], |points| points.array()) {
+                    let coords = point.array();
+                    match (coords.get(0), coords.get(1)) {
+                        (Some(&&Value::Number(x)), Some(&&Value::Number(y))) => points.push((x, y)),
+                        _ => return Err(parser::Error::field("points", Expected::Point)),
+                    }
+                }
+                if points.is_empty() {
+                    return Err(parser::Error::field("points", Expected::Point));
+                }
+                Ok(UnitState::Patrol(points))
+            }
+            "rotate" => Ok(UnitState::Rotate(try!(value.number("angle")))),
+            "shoot" => Ok(UnitState::Shoot(try!(value.id("id")))),
+            "wait" => Ok(UnitState::Wait(try!(value.number("seconds")))),
+            _ => Err(parser::Error::field("kind", Expected::State)),
         }
     }
 }
@@ -121,6 +199,7 @@ impl FromStr for UnitState {
 pub struct UpdateResults {
     pub unit: Option<Unit>,
     pub command: Option<(Id, UnitState)>,
+    pub event: Option<WeaponEvent>,
 }
 
 impl UpdateResults {
@@ -128,13 +207,15 @@ impl UpdateResults {
         UpdateResults {
             unit: None,
             command: None,
+            event: None,
         }
     }
 
-    fn from_unit(unit: Unit) -> UpdateResults {
+    fn from_unit(unit: Unit, event: Option<WeaponEvent>) -> UpdateResults {
         UpdateResults {
             unit: Some(unit),
             command: None,
+            event: event,
         }
     }
 
@@ -142,6 +223,15 @@ impl UpdateResults {
         UpdateResults {
             unit: None,
             command: Some((id, state)),
+            event: None,
+        }
+    }
+
+    fn from_event(event: WeaponEvent) -> UpdateResults {
+        UpdateResults {
+            unit: None,
+            command: None,
+            event: Some(event),
         }
     }
 }
@@ -152,16 +242,35 @@ pub struct Unit {
     pub team: usize,
     color: Color,
     pub pose: Pose,
-    width: f64,
-    speed: f64,
     pub shape: UnitShape,
-    pub role: UnitRole,
+    pub role: Rc<Role>,
     pub state: UnitState,
-    state_queue: Vec<UnitState>,
+    pub health: f64,
+    // Taken from whatever a bullet hits
+    pub damage: f64,
+    // The unit that fired a bullet
+    pub owner: Option<Id>,
+    // How far a projectile has flown, it is spent after its role's `max_range`
+    travelled: f64,
+    pub weapon: Option<Weapon>,
+    // Start out as the role's shapes and can be changed on the fly
+    fov: Vec<[f64; 2]>,
+    range: Vec<[f64; 2]>,
+    // Orders to carry out once the current state is done, first in first out
+    state_queue: VecDeque<UnitState>,
+    // Waypoints of the current move, the next one is last
+    path: Vec<Waypoint>,
+    path_target: Option<Waypoint>,
+    // Timers of the current state, they restart whenever a state is handed over
+    state_time: f64,
+    patrol_index: usize,
+    // Set while a guard walks back to its centre after straying from it
+    returning: bool,
+    tick_time: f64,
 }
 
 impl Unit {
-    pub fn new(role: UnitRole,
+    pub fn new(role: Rc<Role>,
                id: Id,
                x: f64,
                y: f64,
@@ -169,77 +278,156 @@ impl Unit {
                team: usize,
                state: UnitState)
                -> Unit {
-        let (width, speed, color) = match role {
-            UnitRole::Soldier => {
-                let color = if team == 1 {
-                    BLUE
-                } else {
-                    PURPLE
-                };
-                (25.0, 100.0, color)
-            }
-            UnitRole::General => (50.0, 100.0, RED),
-            UnitRole::Bullet => (5.0, 150.0, BLACK),
-        };
-
         Unit {
             id: id,
             team: team,
-            color: color,
+            color: role.color(team),
             pose: Pose::new(x, y, rotation),
-            width: width,
-            speed: speed,
-            shape: UnitShape::new(Vector2::new(width * 0.5, width * 0.5)),
-            role: role,
+            shape: UnitShape::new(Vector2::new(role.width * 0.5, role.width * 0.5)),
             state: state,
-            state_queue: Vec::new(),
+            health: role.health,
+            damage: role.damage,
+            owner: None,
+            travelled: 0.0,
+            weapon: role.weapon(),
+            fov: role.fov.clone(),
+            range: role.range.clone(),
+            role: role,
+            state_queue: VecDeque::new(),
+            path: Vec::new(),
+            path_target: None,
+            state_time: 0.0,
+            patrol_index: 0,
+            returning: false,
+            tick_time: 0.0,
         }
     }
 
-    pub fn update(&mut self, args: &UpdateArgs, views: &Views) -> UpdateResults {
+    pub fn update(&mut self,
+                  args: &UpdateArgs,
+                  snapshot: &Snapshot,
+                  roles: &Roles,
+                  random: &mut Random)
+                  -> UpdateResults {
+        if let Some(ref mut weapon) = self.weapon {
+            weapon.update(args.dt);
+        }
+
+        self.state_time += args.dt;
+
         let (pose, update_state, results) = match self.state {
-            UnitState::Command(id, ref state) => self.update_command(id, state, args.dt, views),
+            UnitState::Command(id, ref state) => self.update_command(id, state, args.dt, snapshot),
+            UnitState::Follow(id, distance) => {
+                let (pose, update_state) = self.update_follow(id, distance, args.dt, snapshot);
+                (pose, update_state, UpdateResults::empty())
+            }
+            UnitState::Guard(_, _, _) | UnitState::Patrol(_) => {
+                (self.update_walk(args.dt), false, UpdateResults::empty())
+            }
             UnitState::Look(x, y) => {
                 let (pose, update_state) = self.update_look(x, y, args.dt);
                 (pose, update_state, UpdateResults::empty())
             }
+            UnitState::Move(x, y) if self.role.projectile => {
+                let (pose, update_state) = self.update_fly(x, y, args.dt);
+                (pose, update_state, UpdateResults::empty())
+            }
             UnitState::Move(x, y) => {
                 let (pose, update_state) = self.update_move(x, y, args.dt);
                 (pose, update_state, UpdateResults::empty())
             }
-            UnitState::Shoot(id) => self.update_shoot(id, args.dt, views),
+            UnitState::Rotate(angle) => {
+                let (pose, update_state) = self.update_rotate(angle, args.dt);
+                (pose, update_state, UpdateResults::empty())
+            }
+            UnitState::Shoot(id) => self.update_shoot(id, args.dt, snapshot, roles, random),
+            UnitState::Wait(seconds) => {
+                (self.pose, self.state_time >= seconds, UpdateResults::empty())
+            }
             UnitState::Idle | _ => return UpdateResults::empty(),
         };
 
         self.pose = pose;
 
+        if self.path.len() > 1 && self.path.last() == Some(&(pose.x, pose.y)) {
+            self.path.pop();
+        }
+
+        // Patrols head for their next point as soon as they reach one, looping
+        // back to the first after the last
+        if let UnitState::Patrol(ref points) = self.state {
+            if points[self.patrol_index] == (pose.x, pose.y) {
+                self.patrol_index = (self.patrol_index + 1) % points.len();
+            }
+        }
+
         if update_state {
-            info!(target: "units",
-                  "{:?} {:?} -> {:?}", self.role, self.state, &self.peek_next_state());
-            self.state = self.next_state()
+            // Bullets are spent once they have flown their full range
+            if self.role.projectile {
+                self.state = UnitState::Dead;
+            } else {
+                info!(target: "units",
+                      "{} {:?} -> {:?}", self.role.name, self.state, &self.peek_next_state());
+                self.next_state();
+            }
         }
 
         results
     }
 
+    // Plans a route whenever the target of a move, patrol or guard changes,
+    // returns false when the target cannot be reached. Projectiles always fly
+    // straight.
+    pub fn find_path(&mut self, navigation: &mut Navigation, scene: &Scene) -> bool {
+        self.update_guard();
+
+        let target = match self.destination() {
+            Some(target) if !self.role.projectile => target,
+            _ => {
+                self.path.clear();
+                self.path_target = None;
+                return true;
+            }
+        };
+
+        if self.path_target == Some(target) {
+            return true;
+        }
+
+        match navigation.find_path(scene, &self.shape, self.xy(), target) {
+            Some(mut path) => {
+                path.reverse();
+                self.path = path;
+                self.path_target = Some(target);
+                true
+            }
+            None => {
+                self.path.clear();
+                self.path_target = None;
+                false
+            }
+        }
+    }
+
     pub fn render<G: Graphics>(&self, _: &RenderArgs, c: &Context, g: &mut G) {
         let (x, y, rotation) = self.pose.render_pose();
         let transform = c.transform.trans(x, y).rot_rad(rotation);
 
-        let half_width = self.width / 2.0;
-        let square = rectangle::square(-half_width, -half_width, self.width);
+        let width = self.role.width;
+        let half_width = width / 2.0;
+        let square = rectangle::square(-half_width, -half_width, width);
         rectangle(self.color, square, transform, g);
 
-        if self.role == UnitRole::Bullet {
+        if self.role.projectile {
             return;
         }
 
-        let nose_width = self.width / 5.0;
+        let nose_width = width / 5.0;
         let nose = [half_width, -nose_width / 2.0, nose_width, nose_width];
         rectangle(self.color, nose, transform, g);
 
-        polygon(LIGHT_GRAY, &FOV_POINTS, transform, g);
-        polygon(GRAY, &RANGE_POINTS, transform, g);
+        polygon(LIGHT_GRAY, &self.fov, transform, g);
+        polygon(GRAY, &self.range, transform, g);
     }
 
     pub fn overlaps(&self, other: &Unit) -> bool {
@@ -264,6 +452,98 @@ impl Unit {
         }
     }
 
+    // The centre and corners of the unit, it is in sight if a ray reaches any of them
+    pub fn sight_points(&self) -> Vec<Point2<f64>> {
+        let half_width = self.role.width / 2.0;
+        let (sin, cos) = self.pose.rotation.sin_cos();
+
+        [[0.0, 0.0],
+         [-half_width, -half_width],
+         [half_width, -half_width],
+         [half_width, half_width],
+         [-half_width, half_width]]
+            .iter()
+            .map(|p| {
+                Point2::new(self.pose.x + p[0] * cos - p[1] * sin,
+                            self.pose.y + p[0] * sin + p[1] * cos)
+            })
+            .collect()
+    }
+
+    // Rays span from their origin to their target, anything hit before reaching
+    // the target is in the way
+    pub fn blocks_ray(&self, ray: &SightRay) -> bool {
+        self.shape.toi_with_ray(&self.pose.isometry(), ray, true).map_or(false, |toi| toi < 1.0)
+    }
+
+    pub fn bounds(&self) -> Bounds {
+        bounding_volume::aabb(&self.shape, &self.pose.isometry())
+    }
+
+    pub fn fov_bounds(&self) -> Bounds {
+        bounding_volume::aabb(&self.fov(), &self.pose.isometry())
+    }
+
+    // The time since the last tick in whole intervals, once an interval has passed
+    pub fn tick(&mut self, dt: f64) -> Option<f64> {
+        if self.role.tick_interval <= 0.0 {
+            return None;
+        }
+
+        self.tick_time += dt;
+        if self.tick_time < self.role.tick_interval {
+            return None;
+        }
+
+        // What is left over counts towards the next tick, so ticks keep to the
+        // interval whatever the frame rate
+        let leftover = self.tick_time % self.role.tick_interval;
+        let elapsed = self.tick_time - leftover;
+        self.tick_time = leftover;
+        Some(elapsed)
+    }
+
+    // Returns true when the damage killed the unit
+    pub fn take_damage(&mut self, damage: f64) -> bool {
+        self.health -= damage;
+        if self.health > 0.0 {
+            return false;
+        }
+
+        self.health = 0.0;
+        self.state = UnitState::Dead;
+        true
+    }
+
+    // Queues an order after every other one, returns true when the unit had
+    // nothing to do and started on it straight away
+    pub fn enqueue(&mut self, state: UnitState) -> bool {
+        self.state_queue.push_back(state);
+        self.start_next_order()
+    }
+
+    // Queues an order to be carried out before every other one
+    pub fn prepend(&mut self, state: UnitState) -> bool {
+        self.state_queue.push_front(state);
+        self.start_next_order()
+    }
+
+    pub fn clear_orders(&mut self) {
+        self.state_queue.clear();
+    }
+
+    pub fn orders(&self) -> &VecDeque<UnitState> {
+        &self.state_queue
+    }
+
+    pub fn set_fov(&mut self, angle: f64, distance: f64) {
+        self.fov = geometry::cone(angle, distance);
+    }
+
+    pub fn set_range(&mut self, angle: f64, distance: f64) {
+        self.range = geometry::cone(angle, distance);
+    }
+
     pub fn xy(&self) -> (f64, f64) {
         (self.pose.x, self.pose.y)
     }
@@ -272,9 +552,9 @@ impl Unit {
                       id: Id,
                       state: &UnitState,
                       dt: f64,
-                      views: &Views)
+                      snapshot: &Snapshot)
                       -> (Pose, bool, UpdateResults) {
-        let &(pose, ref shape) = match views.get(&id) {
+        let &(pose, ref shape) = match snapshot.get(&id) {
             Some(tuple) => tuple,
             None => {
                 return (self.pose, true, UpdateResults::empty());
@@ -285,50 +565,151 @@ impl Unit {
             (self.pose, true, UpdateResults::from_command(id, state.clone()))
         } else {
             let new_pose = self.pose
-                .rotate_towards(pose.x, pose.y, 1.2 * dt)
-                .move_towards(pose.x, pose.y, self.speed * dt);
+                .rotate_towards(pose.x, pose.y, self.role.turn_rate * dt)
+                .move_towards(pose.x, pose.y, self.role.speed * dt);
             (new_pose, false, UpdateResults::empty())
         }
     }
 
-    fn update_look(&self, x: f64, y: f64, dt: f64) -> (Pose, bool) {
+    // Completes once the target is gone, otherwise keeps within `distance` of it
+    fn update_follow(&self,
+                     id: Id,
+                     distance: f64,
+                     dt: f64,
+                     snapshot: &Snapshot)
+                     -> (Pose, bool) {
+        let &(pose, _) = match snapshot.get(&id) {
+            Some(tuple) => tuple,
+            None => return (self.pose, true),
+        };
+
+        let (dx, dy) = (pose.x - self.pose.x, pose.y - self.pose.y);
+        let gap = (dx * dx + dy * dy).sqrt() - distance;
+        if gap <= 0.0 {
+            return (self.pose, false);
+        }
+
         let new_pose = self.pose
-            .rotate_towards(x, y, 1.2 * dt)
-            .move_towards(x, y, self.speed * dt);
-        (new_pose, self.can_see_point(x, y))
+            .rotate_towards(pose.x, pose.y, self.role.turn_rate * dt)
+            .move_towards(pose.x, pose.y, gap.min(self.role.speed * dt));
+        (new_pose, false)
+    }
+
+    // Only turns, completes once the unit faces the point
+    fn update_look(&self, x: f64, y: f64, dt: f64) -> (Pose, bool) {
+        let new_pose = self.pose.rotate_towards(x, y, self.role.turn_rate * dt);
+        let heading = (y - new_pose.y).atan2(x - new_pose.x);
+        (new_pose, geometry::angle_between(new_pose.rotation, heading) < LOOK_EPSILON)
     }
 
     #[allow(float_cmp)]
     fn update_move(&self, x: f64, y: f64, dt: f64) -> (Pose, bool) {
-        let new_pose = self.pose.move_towards(x, y, self.speed * dt);
+        let new_pose = self.step_towards(x, y, dt);
         (new_pose, self.pose.x == x && self.pose.y == y)
     }
 
-    fn update_shoot(&self, id: Id, dt: f64, views: &Views) -> (Pose, bool, UpdateResults) {
-        let &(pose, ref shape) = match views.get(&id) {
+    // Projectiles fly straight on along their heading at their full speed, done
+    // once they reach the target or have flown their range
+    fn update_fly(&mut self, x: f64, y: f64, dt: f64) -> (Pose, bool) {
+        let step = self.role.speed * dt;
+        let (dx, dy) = (x - self.pose.x, y - self.pose.y);
+        if dx * dx + dy * dy <= step * step {
+            return (Pose::new(x, y, self.pose.rotation), true);
+        }
+
+        self.travelled += step;
+        let (sin, cos) = self.pose.rotation.sin_cos();
+        let new_pose = Pose::new(self.pose.x + cos * step,
+                                 self.pose.y + sin * step,
+                                 self.pose.rotation);
+        (new_pose, self.travelled >= self.role.max_range)
+    }
+
+    // Patrols and guards never complete, they walk their points in order over
+    // and over, or back to the centre whenever they stray outside the radius
+    fn update_walk(&self, dt: f64) -> Pose {
+        match self.destination() {
+            Some((x, y)) => self.step_towards(x, y, dt),
+            None => self.pose,
+        }
+    }
+
+    // Turns to face along `angle`, completes once the unit does
+    fn update_rotate(&self, angle: f64, dt: f64) -> (Pose, bool) {
+        let (sin, cos) = angle.sin_cos();
+        self.update_look(self.pose.x + cos, self.pose.y + sin, dt)
+    }
+
+    // Walks towards the next waypoint of the planned path, or straight for the
+    // target when there is none
+    fn step_towards(&self, x: f64, y: f64, dt: f64) -> Pose {
+        let (next_x, next_y) = self.path.last().cloned().unwrap_or((x, y));
+        self.pose.move_towards(next_x, next_y, self.role.speed * dt)
+    }
+
+    // Where the current state is walking to, if anywhere
+    fn destination(&self) -> Option<Waypoint> {
+        match self.state {
+            UnitState::Move(x, y) => Some((x, y)),
+            UnitState::Patrol(ref points) => points.get(self.patrol_index).cloned(),
+            UnitState::Guard(x, y, _) if self.returning => Some((x, y)),
+            _ => None,
+        }
+    }
+
+    // Keeps firing at the target while it is in range, until the weapon runs dry
+    fn update_shoot(&mut self,
+                    id: Id,
+                    dt: f64,
+                    snapshot: &Snapshot,
+                    roles: &Roles,
+                    random: &mut Random)
+                    -> (Pose, bool, UpdateResults) {
+        let &(pose, ref shape) = match snapshot.get(&id) {
             Some(tuple) => tuple,
             None => {
                 return (self.pose, true, UpdateResults::empty());
             }
         };
 
-        if self.can_shoot(&pose, shape) {
-            let bullet_pose = self.pose.move_towards(pose.x, pose.y, self.width);
-            let bullet = Unit::new(UnitRole::Bullet,
-                                   Id::new_v4(),
-                                   bullet_pose.x,
-                                   bullet_pose.y,
-                                   bullet_pose.rotation,
-                                   self.team,
-                                   UnitState::Move(pose.x, pose.y));
-            (self.pose, true, UpdateResults::from_unit(bullet))
-        } else {
+        if !self.can_shoot(&pose, shape) {
             let new_pose = self.pose
-                .rotate_towards(pose.x, pose.y, 1.2 * dt)
-                .move_towards(pose.x, pose.y, self.speed * dt);
-            (new_pose, false, UpdateResults::empty())
+                .rotate_towards(pose.x, pose.y, self.role.turn_rate * dt)
+                .move_towards(pose.x, pose.y, self.role.speed * dt);
+            return (new_pose, false, UpdateResults::empty());
         }
 
+        let event = match self.weapon {
+            Some(ref weapon) if weapon.is_empty() => {
+                return (self.pose, true, UpdateResults::from_event(WeaponEvent::OutOfAmmo))
+            }
+            Some(ref mut weapon) if weapon.ready() => weapon.fire(),
+            Some(_) => return (self.pose, false, UpdateResults::empty()),
+            None => return (self.pose, true, UpdateResults::empty()),
+        };
+
+        // Fired towards where the target is now, the bullet flies straight on past
+        // it until it hits something or reaches its range. It starts out clear of
+        // the shooter whichever way either of them is turned.
+        let heading = (pose.y - self.pose.y).atan2(pose.x - self.pose.x);
+        let (sin, cos) = heading.sin_cos();
+        let role = roles.get(role::BULLET).unwrap();
+        let max_range = role.max_range;
+        let offset = (self.role.width + role.width) * 0.5 * f64::consts::SQRT_2;
+        let bullet_pose = Pose::new(self.pose.x + cos * offset,
+                                    self.pose.y + sin * offset,
+                                    heading);
+        let mut bullet = Unit::new(role,
+                               random.gen_id(),
+                               bullet_pose.x,
+                               bullet_pose.y,
+                               heading,
+                               self.team,
+                               UnitState::Move(bullet_pose.x + cos * max_range,
+                                               bullet_pose.y + sin * max_range));
+        bullet.owner = Some(self.id);
+
+        (self.pose, event == Some(WeaponEvent::OutOfAmmo), UpdateResults::from_unit(bullet, event))
     }
 
     fn can_shoot(&self, pose: &Pose, shape: &UnitShape) -> bool {
@@ -342,27 +723,80 @@ impl Unit {
         }
     }
 
-    fn can_see_point(&self, x: f64, y: f64) -> bool {
-        self.fov().contains_point(&self.pose.isometry(), &Point2::new(x, y))
-    }
-
     fn fov(&self) -> ConvexHull<Point2<f64>> {
-        ConvexHull::new(FOV_POINTS.iter().map(|p| Point2::new(p[0], p[1])).collect())
+        ConvexHull::new(self.fov.iter().map(|p| Point2::new(p[0], p[1])).collect())
     }
 
     fn range(&self) -> ConvexHull<Point2<f64>> {
-        ConvexHull::new(RANGE_POINTS.iter().map(|p| Point2::new(p[0], p[1])).collect())
+        ConvexHull::new(self.range.iter().map(|p| Point2::new(p[0], p[1])).collect())
     }
 
-    fn next_state(&mut self) -> UnitState {
-        self.state_queue.pop().unwrap_or(UnitState::Idle)
+    fn start_next_order(&mut self) -> bool {
+        if self.state != UnitState::Idle {
+            return false;
+        }
+        self.next_state();
+        true
+    }
+
+    // A guard that strays outside its radius heads back, and keeps going until
+    // it is at the centre again
+    #[allow(float_cmp)]
+    fn update_guard(&mut self) {
+        if let UnitState::Guard(x, y, radius) = self.state {
+            let (dx, dy) = (x - self.pose.x, y - self.pose.y);
+            if dx * dx + dy * dy > radius * radius {
+                self.returning = true;
+            } else if self.pose.x == x && self.pose.y == y {
+                self.returning = false;
+            }
+        }
+    }
+
+    // Moves on to the next order, or goes idle when there is none
+    pub fn next_state(&mut self) {
+        let state = self.state_queue.pop_front().unwrap_or(UnitState::Idle);
+        self.set_state(state);
+    }
+
+    // Hands the unit a state, restarting its timers even when it is the same
+    // state again, two `wait(1)` in a row wait for 2 seconds
+    pub fn set_state(&mut self, state: UnitState) {
+        self.state = state;
+        self.state_time = 0.0;
+        self.patrol_index = 0;
+        self.returning = false;
     }
 
     fn peek_next_state(&self) -> &UnitState {
-        if self.state_queue.is_empty() {
-            IDLE
-        } else {
-            &self.state_queue[0]
+        self.state_queue.front().unwrap_or(IDLE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use piston_window::UpdateArgs;
+
+    use random::{Random, Stream};
+    use role::Roles;
+    use super::{Id, Snapshot, Unit, UnitState};
+
+    #[test]
+    fn equal_waits_each_wait_in_full() {
+        let roles = Roles::new(vec![]);
+        let soldier = roles.get("soldier").unwrap();
+        let mut unit = Unit::new(soldier, Id::new_v4(), 0.0, 0.0, 0.0, 1, UnitState::Idle);
+        unit.enqueue(UnitState::Wait(1.0));
+        unit.enqueue(UnitState::Wait(1.0));
+
+        let args = UpdateArgs { dt: 0.25 };
+        let mut random = Random::new(1, Stream::State);
+        let mut ticks = 0;
+        while unit.state != UnitState::Idle && ticks < 100 {
+            unit.update(&args, &Snapshot::new(), &roles, &mut random);
+            ticks += 1;
         }
+
+        assert_eq!(ticks, 8);
     }
 }
@@ -0,0 +1,210 @@
+use hlua::{Lua, LuaError, LuaTable};
+use std::collections::BTreeMap;
+
+use parser::{self, Expected};
+use unit::Id;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Key {
+    Index(i64),
+    Name(String),
+}
+
+// Any Lua value that can be converted into Rust, tables are kept in a sorted
+// map so that their iteration order does not depend on Lua's hashing
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Table(BTreeMap<Key, Value>),
+}
+
+enum Token {
+    Open,
+    Close,
+    Value(Value),
+}
+
+impl Value {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        match *self {
+            Value::Table(ref table) => table.get(&Key::Name(name.to_string())),
+            _ => None,
+        }
+    }
+
+    // The values stored under 1..n, like Lua's ipairs
+    pub fn array(&self) -> Vec<&Value> {
+        let mut values = vec![];
+
+        if let Value::Table(ref table) = *self {
+            while let Some(value) = table.get(&Key::Index(values.len() as i64 + 1)) {
+                values.push(value);
+            }
+        }
+
+        values
+    }
+
+    // The values stored under string keys, in key order
+    pub fn fields(&self) -> Vec<(&str, &Value)> {
+        let mut fields = vec![];
+
+        if let Value::Table(ref table) = *self {
+            for (key, value) in table {
+                if let Key::Name(ref name) = *key {
+                    fields.push((name.as_str(), value));
+                }
+            }
+        }
+
+        fields
+    }
+
+    pub fn string(&self, name: &str) -> Result<&str, parser::Error> {
+        match self.get(name) {
+            Some(&Value::String(ref s)) => Ok(s),
+            _ => Err(parser::Error::field(name, Expected::Symbol)),
+        }
+    }
+
+    pub fn number(&self, name: &str) -> Result<f64, parser::Error> {
+        match self.get(name) {
+            Some(&Value::Number(n)) if n.is_finite() => Ok(n),
+            _ => Err(parser::Error::field(name, Expected::Float)),
+        }
+    }
+
+    pub fn int(&self, name: &str) -> Result<usize, parser::Error> {
+        match self.get(name) {
+            Some(&Value::Number(n)) if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+            _ => Err(parser::Error::field(name, Expected::Int)),
+        }
+    }
+
+    pub fn id(&self, name: &str) -> Result<Id, parser::Error> {
+        let s = try!(self.string(name));
+        Id::parse_str(s).map_err(|_| parser::Error::field(name, Expected::Id))
+    }
+
+    // Written out as a Lua literal, which is how nested tables are handed back
+    // to Lua
+    pub fn to_lua(&self) -> String {
+        match *self {
+            Value::Boolean(b) => b.to_string(),
+            Value::Number(n) if n.is_nan() => "(0/0)".to_string(),
+            Value::Number(n) if n.is_infinite() && n > 0.0 => "math.huge".to_string(),
+            Value::Number(n) if n.is_infinite() => "-math.huge".to_string(),
+            Value::Number(n) => format!("{:?}", n),
+            Value::String(ref s) => quote(s),
+            Value::Table(ref table) => {
+                let fields = table.iter()
+                    .map(|(key, value)| {
+                        let key = match *key {
+                            Key::Index(i) => i.to_string(),
+                            Key::Name(ref name) => quote(name),
+                        };
+                        format!("[{}] = {}", key, value.to_lua())
+                    })
+                    .collect::<Vec<_>>();
+                format!("{{{}}}", fields.join(", "))
+            }
+        }
+    }
+}
+
+// Anything but printable ASCII is escaped byte by byte, which Lua reads back
+// into the same string
+fn quote(s: &str) -> String {
+    let mut quoted = "\"".to_string();
+    for byte in s.bytes() {
+        match byte {
+            b'"' | b'\\' => quoted.push_str(&format!("\\{}", byte as char)),
+            byte if byte >= b' ' && byte <= b'~' => quoted.push(byte as char),
+            _ => quoted.push_str(&format!("\\{:03}", byte)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// hlua can only read one level of a table at a time, so `__encode` in core.lua
+// first flattens the global into a list of tagged primitives
+pub fn read_global(lua: &mut Lua, name: &str) -> Result<Option<Value>, LuaError> {
+    try!(lua.execute::<()>(&format!("__tokens = __encode({})", name)));
+    let len: u32 = try!(lua.execute("return #__tokens"));
+
+    let mut table: LuaTable<_> = match lua.get("__tokens") {
+        Some(table) => table,
+        None => return Err(LuaError::WrongType),
+    };
+
+    let mut tokens = vec![];
+    let mut i = 1;
+
+    while i <= len {
+        let tag: String = match table.get(i) {
+            Some(tag) => tag,
+            None => return Err(LuaError::WrongType),
+        };
+
+        let value = match tag.as_str() {
+            "{" => Some(Token::Open),
+            "}" => Some(Token::Close),
+            "b" => table.get(i + 1).map(|b| Token::Value(Value::Boolean(b))),
+            "n" => table.get(i + 1).map(|n| Token::Value(Value::Number(n))),
+            "s" => table.get(i + 1).map(|s| Token::Value(Value::String(s))),
+            _ => None,
+        };
+
+        match value {
+            Some(Token::Value(value)) => {
+                tokens.push(Token::Value(value));
+                i += 2;
+            }
+            Some(token) => {
+                tokens.push(token);
+                i += 1;
+            }
+            None => return Err(LuaError::WrongType),
+        }
+    }
+
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+
+    let mut tokens = tokens.into_iter();
+    match decode(&mut tokens) {
+        Some(value) => Ok(Some(value)),
+        None => Err(LuaError::WrongType),
+    }
+}
+
+fn decode<I: Iterator<Item = Token>>(tokens: &mut I) -> Option<Value> {
+    match tokens.next() {
+        Some(Token::Value(value)) => Some(value),
+        Some(Token::Open) => {
+            let mut table = BTreeMap::new();
+
+            loop {
+                let key = match tokens.next() {
+                    Some(Token::Close) => return Some(Value::Table(table)),
+                    Some(Token::Value(Value::String(s))) => Key::Name(s),
+                    Some(Token::Value(Value::Number(n))) if n.fract() == 0.0 => {
+                        Key::Index(n as i64)
+                    }
+                    _ => return None,
+                };
+
+                let value = match decode(tokens) {
+                    Some(value) => value,
+                    None => return None,
+                };
+                table.insert(key, value);
+            }
+        }
+        Some(Token::Close) | None => None,
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use time;
+
+use interpreter;
+
+const POLL_INTERVAL: u64 = 500000000;
+
+type Mtimes = BTreeMap<PathBuf, SystemTime>;
+
+// Polls the modification times of the scripts in a directory, there are only a
+// handful of them so this is cheaper than pulling in a file system notifier
+pub struct Watcher {
+    dir: String,
+    mtimes: Mtimes,
+    polled_at: u64,
+}
+
+impl Watcher {
+    pub fn new(dir: &str) -> Watcher {
+        Watcher {
+            dir: dir.to_string(),
+            mtimes: Self::scan(dir).unwrap_or_else(|_| Mtimes::new()),
+            polled_at: time::precise_time_ns(),
+        }
+    }
+
+    // Every script added or modified since the last poll
+    pub fn changed(&mut self) -> Vec<PathBuf> {
+        let now = time::precise_time_ns();
+        if now - self.polled_at < POLL_INTERVAL {
+            return vec![];
+        }
+        self.polled_at = now;
+
+        let mtimes = match Self::scan(&self.dir) {
+            Ok(mtimes) => mtimes,
+            Err(err) => {
+                warn!(target: "reload", "could not scan {}: {}", self.dir, err);
+                return vec![];
+            }
+        };
+
+        let changed = mtimes.iter()
+            .filter(|&(path, mtime)| self.mtimes.get(path) != Some(mtime))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        self.mtimes = mtimes;
+        changed
+    }
+
+    fn scan(dir: &str) -> Result<Mtimes, io::Error> {
+        let mut mtimes = Mtimes::new();
+
+        for path in try!(interpreter::script_paths(dir)) {
+            let mtime = try!(try!(fs::metadata(&path)).modified());
+            mtimes.insert(path, mtime);
+        }
+
+        Ok(mtimes)
+    }
+}
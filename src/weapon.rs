@@ -0,0 +1,72 @@
+// What firing a round or running a weapon's timers did, handed on to Lua
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WeaponEvent {
+    Reload,
+    OutOfAmmo,
+}
+
+// Rounds are fired from the magazine, once it is empty it is refilled from the
+// reserve, which takes `reload_time` seconds
+#[derive(Clone, Debug, PartialEq)]
+pub struct Weapon {
+    fire_interval: f64,
+    magazine_size: usize,
+    reload_time: f64,
+    pub magazine: usize,
+    pub reserve: usize,
+    cooldown: f64,
+    reloading: bool,
+}
+
+impl Weapon {
+    pub fn new(fire_interval: f64,
+               magazine_size: usize,
+               reload_time: f64,
+               reserve: usize)
+               -> Weapon {
+        Weapon {
+            fire_interval: fire_interval,
+            magazine_size: magazine_size,
+            reload_time: reload_time,
+            magazine: magazine_size,
+            reserve: reserve,
+            cooldown: 0.0,
+            reloading: false,
+        }
+    }
+
+    pub fn update(&mut self, dt: f64) {
+        self.cooldown = (self.cooldown - dt).max(0.0);
+
+        if self.reloading && self.cooldown <= 0.0 {
+            let rounds = self.magazine_size.min(self.reserve);
+            self.magazine += rounds;
+            self.reserve -= rounds;
+            self.reloading = false;
+        }
+    }
+
+    pub fn ready(&self) -> bool {
+        !self.reloading && self.cooldown <= 0.0 && self.magazine > 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.magazine == 0 && self.reserve == 0
+    }
+
+    // Uses up a round, and starts reloading when it was the last one in the magazine
+    pub fn fire(&mut self) -> Option<WeaponEvent> {
+        self.magazine -= 1;
+        self.cooldown = self.fire_interval;
+
+        if self.magazine > 0 {
+            None
+        } else if self.reserve > 0 {
+            self.reloading = true;
+            self.cooldown = self.reload_time;
+            Some(WeaponEvent::Reload)
+        } else {
+            Some(WeaponEvent::OutOfAmmo)
+        }
+    }
+}